@@ -0,0 +1,255 @@
+//! Access control for rooms protected by a passphrase or invite codes.
+//!
+//! Joiners prove they know a secret by answering a random challenge with an HMAC keyed by it,
+//! so the secret itself never crosses the wire. Once admitted, they're handed the room key,
+//! which gets them past the challenge of whoever leads the room after a failover, since the
+//! new admin doesn't know the original secrets.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::entities::Payload;
+
+/// Rounds of PBKDF2 secrets are stretched with, to slow down guessing them from a recorded
+/// handshake
+const PBKDF2_ROUNDS: u32 = 100_000;
+/// Letters invite codes are made of, leaving out ones that are easily confused
+const INVITE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+pub type Key = [u8; 32];
+pub type Nonce = [u8; 32];
+
+/// Stretch a secret typed by a person into a key, salted with the room it's for
+pub fn derive(secret: &str, room_id: u64) -> Key {
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(
+        secret.trim().as_bytes(),
+        &room_id.to_le_bytes(),
+        PBKDF2_ROUNDS,
+        &mut key,
+    );
+    key
+}
+
+fn mac(key: &Key, context: &[u8], nonce: &Nonce) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).unwrap();
+    mac.update(context);
+    mac.update(nonce);
+    mac
+}
+
+/// Answer to the challenge `nonce`, showing we know `key`
+pub fn prove(key: &Key, nonce: &Nonce) -> [u8; 32] {
+    mac(key, b"proof", nonce).finalize().into_bytes().into()
+}
+
+fn verify(key: &Key, nonce: &Nonce, proof: &[u8; 32]) -> bool {
+    mac(key, b"proof", nonce).verify_slice(proof).is_ok()
+}
+
+/// Cipher for handing the room key to whoever answered `nonce` with `key`
+fn admission_cipher(key: &Key, nonce: &Nonce) -> ChaCha20Poly1305 {
+    let key: [u8; 32] = mac(key, b"admission", nonce).finalize().into_bytes().into();
+    ChaCha20Poly1305::new(&key.into())
+}
+
+/// Open the room key the admin sealed for us after we answered `nonce` with `key`
+pub fn open_room_key(sealed: &[u8], key: &Key, nonce: &Nonce) -> Option<Key> {
+    // The cipher key is unique to the nonce, so the nonce of the cipher itself needn't be
+    let room_key = admission_cipher(key, nonce)
+        .decrypt(&[0; 12].into(), sealed)
+        .ok()?;
+    room_key.try_into().ok()
+}
+
+/// Secrets guarding a room we lead, and the joiners we challenged
+#[derive(Clone)]
+pub struct Protection {
+    room_key: Key,
+    passphrase: Option<Key>,
+    /// Outstanding one-time invite codes and their keys
+    invites: HashMap<String, Key>,
+    /// Challenges sent to joiners, along with the requests they made
+    challenges: HashMap<u32, (Nonce, Payload)>,
+    /// Connections that answered their challenge
+    admitted: HashSet<u32>,
+}
+
+impl Protection {
+    pub fn new(room_id: u64, passphrase: Option<&str>) -> Self {
+        Protection {
+            passphrase: passphrase.map(|p| derive(p, room_id)),
+            ..Self::from_room_key(rand::random())
+        }
+    }
+
+    /// Protection of a room we took over, which only lets in peers holding its key
+    pub fn from_room_key(room_key: Key) -> Self {
+        Protection {
+            room_key,
+            passphrase: None,
+            invites: HashMap::new(),
+            challenges: HashMap::new(),
+            admitted: HashSet::new(),
+        }
+    }
+
+    pub fn room_key(&self) -> Key {
+        self.room_key
+    }
+
+    /// Create a code that lets one peer join the room `room_id`
+    pub fn invite(&mut self, room_id: u64) -> String {
+        let letters: String = (0..8)
+            .map(|_| INVITE_ALPHABET[rand::random_range(0..INVITE_ALPHABET.len())] as char)
+            .collect();
+        let code = format!("{}-{}", &letters[..4], &letters[4..]);
+        self.invites.insert(code.clone(), derive(&code, room_id));
+        code
+    }
+
+    pub fn invites(&self) -> impl Iterator<Item = &String> {
+        self.invites.keys()
+    }
+
+    pub fn admitted(&self, con_id: u32) -> bool {
+        self.admitted.contains(&con_id)
+    }
+
+    /// Hold `request` from `con_id` back until it answers the returned challenge
+    pub fn challenge(&mut self, con_id: u32, request: Payload) -> Nonce {
+        let nonce = rand::random();
+        self.challenges.insert(con_id, (nonce, request));
+        nonce
+    }
+
+    /// Check the answer of `con_id` to its challenge, using up the invite code it proved if any.
+    ///
+    /// Returns the request it made, and the room key sealed for it.
+    pub fn respond(&mut self, con_id: u32, proof: &[u8; 32]) -> Option<(Payload, Vec<u8>)> {
+        let (nonce, request) = self.challenges.remove(&con_id)?;
+
+        let key = if verify(&self.room_key, &nonce, proof) {
+            self.room_key
+        } else if let Some(passphrase) = self.passphrase.filter(|k| verify(k, &nonce, proof)) {
+            passphrase
+        } else {
+            let code = self
+                .invites
+                .iter()
+                .find(|(_, key)| verify(key, &nonce, proof))
+                .map(|(code, _)| code.clone())?;
+            log::info!("Invite code {code} was used");
+            self.invites.remove(&code)?
+        };
+
+        self.admitted.insert(con_id);
+        let sealed = admission_cipher(&key, &nonce)
+            .encrypt(&[0; 12].into(), self.room_key.as_slice())
+            .unwrap();
+        Some((request, sealed))
+    }
+
+    /// Drop what we know about a closed connection
+    pub fn forget(&mut self, con_id: u32) {
+        self.challenges.remove(&con_id);
+        self.admitted.remove(&con_id);
+    }
+}
+
+// Keeps the secrets out of the logs
+impl fmt::Debug for Protection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Protection")
+            .field("passphrase", &self.passphrase.is_some())
+            .field("invites", &self.invites.len())
+            .field("admitted", &self.admitted)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: u64 = 7;
+
+    #[test]
+    fn admits_with_the_passphrase() {
+        let mut protection = Protection::new(ROOM, Some("hunter2"));
+        let key = derive("hunter2", ROOM);
+        let nonce = protection.challenge(1, Payload::Leave);
+
+        let (request, sealed) = protection.respond(1, &prove(&key, &nonce)).unwrap();
+        assert!(matches!(request, Payload::Leave));
+        assert!(protection.admitted(1));
+        assert_eq!(
+            open_room_key(&sealed, &key, &nonce),
+            Some(protection.room_key())
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_passphrase() {
+        let mut protection = Protection::new(ROOM, Some("hunter2"));
+        let nonce = protection.challenge(1, Payload::Leave);
+        let proof = prove(&derive("hunter3", ROOM), &nonce);
+        assert!(protection.respond(1, &proof).is_none());
+        assert!(!protection.admitted(1));
+    }
+
+    #[test]
+    fn rejects_a_proof_of_another_challenge() {
+        let mut protection = Protection::from_room_key([1; 32]);
+        let nonce = protection.challenge(1, Payload::Leave);
+        protection.challenge(2, Payload::Leave);
+        assert!(protection.respond(2, &prove(&[1; 32], &nonce)).is_none());
+        assert!(!protection.admitted(2));
+    }
+
+    #[test]
+    fn rejects_a_wrong_proof_once_and_for_all() {
+        let mut protection = Protection::from_room_key([1; 32]);
+        let nonce = protection.challenge(1, Payload::Leave);
+        assert!(protection.respond(1, &[0; 32]).is_none());
+        // The challenge is used up, so the right answer doesn't get a second try
+        assert!(protection.respond(1, &prove(&[1; 32], &nonce)).is_none());
+        assert!(!protection.admitted(1));
+    }
+
+    #[test]
+    fn admits_with_the_room_key_after_a_failover() {
+        let mut protection = Protection::from_room_key([1; 32]);
+        let nonce = protection.challenge(1, Payload::Leave);
+        assert!(protection.respond(1, &prove(&[1; 32], &nonce)).is_some());
+        assert!(protection.admitted(1));
+    }
+
+    #[test]
+    fn invite_codes_work_once() {
+        let mut protection = Protection::new(ROOM, None);
+        let code = protection.invite(ROOM);
+        let key = derive(&code, ROOM);
+
+        let nonce = protection.challenge(1, Payload::Leave);
+        assert!(protection.respond(1, &prove(&key, &nonce)).is_some());
+        let nonce = protection.challenge(2, Payload::Leave);
+        assert!(protection.respond(2, &prove(&key, &nonce)).is_none());
+        assert_eq!(protection.invites().count(), 0);
+    }
+
+    #[test]
+    fn room_key_only_opens_with_the_proven_key() {
+        let mut protection = Protection::from_room_key([1; 32]);
+        let nonce = protection.challenge(1, Payload::Leave);
+        let (_, sealed) = protection.respond(1, &prove(&[1; 32], &nonce)).unwrap();
+        assert!(open_room_key(&sealed, &[2; 32], &nonce).is_none());
+        assert!(open_room_key(&sealed, &[1; 32], &[0; 32]).is_none());
+    }
+}
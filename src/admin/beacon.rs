@@ -0,0 +1,156 @@
+use std::{
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{unbounded, Sender, TryRecvError};
+use postcard::{from_bytes, to_allocvec};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+use crate::{
+    entities::{beacon_addr, beacon_addr_v6, Beacon, Datagram},
+    ip,
+};
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Periodically multicasts the latest [`Beacon`] for the room we administer,
+/// and answers latency probes from peers browsing the lobby.
+///
+/// The announcing threads stop once every clone of the announcer is dropped.
+#[derive(Debug, Clone)]
+pub struct Announcer {
+    /// One per address family we announce over
+    beacon_txs: Vec<Sender<Beacon>>,
+}
+
+impl Announcer {
+    /// Announce `beacon` over IPv4, and over IPv6 on every interface that has it
+    pub fn spawn(beacon: Beacon) -> io::Result<Self> {
+        let mut beacon_txs = Vec::new();
+        let mut failure = None;
+        for (family, channel) in [("IPv4", ipv4()), ("IPv6", ipv6())] {
+            match channel.and_then(|(socket, targets)| announce(socket, targets, beacon.clone())) {
+                Ok(beacon_tx) => beacon_txs.push(beacon_tx),
+                Err(e) => {
+                    log::info!("Not announcing over {family}: {e}");
+                    failure = Some(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) if beacon_txs.is_empty() => Err(e),
+            _ => Ok(Self { beacon_txs }),
+        }
+    }
+
+    /// Replace the beacon being announced, e.g. after the member count changed
+    pub fn update(&self, beacon: Beacon) {
+        for beacon_tx in &self.beacon_txs {
+            let _ = beacon_tx.send(beacon.clone());
+        }
+    }
+}
+
+/// Where a socket sends beacons to, along with the interface to send them from if it has to
+/// be picked
+struct Target {
+    interface: Option<u32>,
+    addr: SocketAddr,
+}
+
+fn ipv4() -> io::Result<(Socket, Vec<Target>)> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).into())?;
+    let target = Target {
+        interface: None,
+        addr: SocketAddr::V4(beacon_addr()),
+    };
+    Ok((socket, vec![target]))
+}
+
+/// Link-local multicast only reaches the link it's sent on, so it's sent on each of them
+fn ipv6() -> io::Result<(Socket, Vec<Target>)> {
+    let targets: Vec<Target> = ip::ipv6_interfaces()
+        .into_iter()
+        .map(|index| Target {
+            interface: Some(index),
+            addr: SocketAddr::V6(beacon_addr_v6(index)),
+        })
+        .collect();
+    if targets.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no interface has an IPv6 address",
+        ));
+    }
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_multicast_loop_v6(true)?;
+    socket.set_multicast_hops_v6(1)?;
+    socket.bind(&SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0)).into())?;
+    Ok((socket, targets))
+}
+
+/// Multicast `beacon` to `targets` from a thread of its own, until the returned sender of
+/// updated beacons is dropped
+fn announce(socket: Socket, targets: Vec<Target>, beacon: Beacon) -> io::Result<Sender<Beacon>> {
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    let (beacon_tx, beacon_rx) = unbounded::<Beacon>();
+
+    std::thread::Builder::new()
+        .name("beacon announcer".into())
+        .spawn(move || {
+            let mut current = beacon;
+            let mut last_sent: Option<Instant> = None;
+            let mut buf = [0u8; 1024];
+            loop {
+                match beacon_rx.try_recv() {
+                    Ok(beacon) => {
+                        current = beacon;
+                        last_sent = None;
+                    }
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => break,
+                }
+
+                if last_sent.is_none_or(|t| t.elapsed() >= ANNOUNCE_INTERVAL) {
+                    match to_allocvec(&Datagram::Beacon(current.clone())) {
+                        Ok(bytes) => {
+                            for target in &targets {
+                                let sent = match target.interface {
+                                    Some(index) => {
+                                        SockRef::from(&socket).set_multicast_if_v6(index)
+                                    }
+                                    None => Ok(()),
+                                }
+                                .and_then(|_| socket.send_to(&bytes, target.addr));
+                                if let Err(e) = sent {
+                                    log::warn!("Failed to announce room: {e}");
+                                }
+                            }
+                        }
+                        Err(e) => log::error!("Failed to encode beacon: {e}"),
+                    }
+                    last_sent = Some(Instant::now());
+                }
+
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if let Ok(Datagram::Ping(nonce)) = from_bytes(&buf[..len]) {
+                    if let Ok(reply) = to_allocvec(&Datagram::Pong(nonce)) {
+                        let _ = socket.send_to(&reply, from);
+                    }
+                }
+            }
+            log::info!("Beacon announcer stopped");
+        })?;
+
+    Ok(beacon_tx)
+}
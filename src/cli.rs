@@ -0,0 +1,162 @@
+//! Flags, subcommands and arguments on the command line.
+
+use std::{net::IpAddr, path::PathBuf};
+
+use clap::{Parser, Subcommand};
+use color_eyre::{eyre::eyre, Result};
+use log::LevelFilter;
+use vlawn::{Endpoint, RoomOptions};
+
+use crate::config::Config;
+
+/// Chat rooms on the LAN, without a server
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file to read instead of config.toml in the XDG config directory
+    #[arg(long, global = true, env = "VLAWN_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Name to go by instead of the login name
+    #[arg(long, global = true)]
+    pub nick: Option<String>,
+    /// Port to take connections from other peers on
+    #[arg(long, global = true, env = "VLAWN_PORT")]
+    pub port: Option<u16>,
+    /// Address to take connections from other peers at, instead of every one
+    #[arg(long, global = true, env = "VLAWN_BIND")]
+    pub bind: Option<IpAddr>,
+    /// Address, or name of the network interface, other peers should reach us at
+    #[arg(long, global = true, env = "VLAWN_ADDR")]
+    pub interface: Option<String>,
+    /// File to append the log to
+    #[arg(long, global = true)]
+    pub log_file: Option<PathBuf>,
+    /// How much to log: off, error, warn, info, debug or trace
+    #[arg(long, global = true)]
+    pub log_level: Option<LevelFilter>,
+    /// Read lines from stdin and print the room to stdout, instead of the terminal UI
+    #[arg(long, global = true)]
+    pub headless: bool,
+    /// Encrypt links to other peers, which then need it on as well
+    #[arg(long, global = true)]
+    pub tls: bool,
+    /// Seconds between pings to other peers
+    #[arg(long, global = true, value_name = "SECS")]
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds other peers may stay silent before they're dropped
+    #[arg(long, global = true, value_name = "SECS")]
+    pub heartbeat_timeout: Option<u64>,
+}
+
+/// Without one, the rooms on the LAN are browsed, or the configured host or room joined
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Create a room and lead it
+    Host {
+        /// Name of the room, instead of a random one
+        #[arg(long)]
+        name: Option<String>,
+        /// Passphrase joining peers have to give
+        #[arg(long, env = "VLAWN_PASSPHRASE")]
+        passphrase: Option<String>,
+    },
+    /// Join a room
+    Join {
+        /// Host leading the room, as host[:port], or name of a room on the LAN
+        target: String,
+        /// Passphrase or invite code to give if the room asks for one
+        #[arg(long, env = "VLAWN_PASSPHRASE")]
+        passphrase: Option<String>,
+    },
+    /// List the rooms on the LAN and exit
+    List {
+        /// Seconds to listen for rooms announcing themselves
+        #[arg(long, default_value_t = 3)]
+        wait: u64,
+    },
+    /// Send a message to a room and exit
+    Send {
+        /// Host leading the room, as host[:port], or name of a room on the LAN
+        room: String,
+        message: String,
+        /// Passphrase or invite code to give if the room asks for one
+        #[arg(long, env = "VLAWN_PASSPHRASE")]
+        passphrase: Option<String>,
+    },
+    /// Print the messages stored of a room we were in
+    History {
+        /// Name or id of the room
+        room: String,
+    },
+}
+
+/// Where to go once started
+#[derive(Debug)]
+pub enum Start {
+    /// Browse the rooms on the LAN, or hold a room of our own without a lobby
+    Lobby,
+    Host(RoomOptions),
+    /// Join the room led at `endpoint`, which `host` resolved to
+    Join {
+        host: String,
+        endpoint: Endpoint,
+    },
+    /// Join the room called `name` once it's discovered, with why it isn't a host if it was
+    /// given as either
+    Find {
+        name: String,
+        unresolved: Option<String>,
+    },
+}
+
+impl Start {
+    /// Where `command` goes, or the host or room in `config` without one
+    pub fn new(command: Option<&Command>, config: &Config) -> Result<Self> {
+        let start = match command {
+            Some(Command::Host { name, passphrase }) => Start::Host(RoomOptions {
+                name: name.clone(),
+                passphrase: passphrase.clone(),
+                ..RoomOptions::default()
+            }),
+            Some(Command::Join { target, .. } | Command::Send { room: target, .. }) => {
+                Start::target(target)
+            }
+            _ => match (&config.host, &config.room) {
+                (Some(host), _) => match Endpoint::resolve(host) {
+                    Ok(endpoint) => Start::Join {
+                        host: host.clone(),
+                        endpoint,
+                    },
+                    Err(e) => {
+                        return Err(eyre!("Could not resolve the configured host {host}: {e}"))
+                    }
+                },
+                (None, Some(room)) => Start::Find {
+                    name: room.clone(),
+                    unresolved: None,
+                },
+                (None, None) => Start::Lobby,
+            },
+        };
+        Ok(start)
+    }
+
+    /// Join `target` as a host if it resolves to one, else as the name of a room on the LAN
+    fn target(target: &str) -> Self {
+        match Endpoint::resolve(target) {
+            Ok(endpoint) => Start::Join {
+                host: target.to_string(),
+                endpoint,
+            },
+            Err(e) => {
+                log::info!("{target} isn't a host, looking for a room by that name: {e}");
+                Start::Find {
+                    name: target.to_string(),
+                    unresolved: Some(e.to_string()),
+                }
+            }
+        }
+    }
+}
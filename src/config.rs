@@ -0,0 +1,422 @@
+//! Settings read from `config.toml` in the XDG config directory, so each user sets vlawn up
+//! once. Flags given on the command line win over the file.
+//!
+//! ```toml
+//! name = "ada"
+//! room = "quiet-otter-hums"
+//! port = 57185
+//! bind = "192.168.1.20"
+//! interface = "eth0"
+//! headless = false
+//! tls = true
+//! heartbeat_interval = 2
+//! heartbeat_timeout = 6
+//!
+//! [log]
+//! path = "~/.local/state/vlawn.log"
+//! level = "debug"
+//!
+//! [theme]
+//! background = 235
+//! notice = "#5fafaf"
+//!
+//! [keys]
+//! quit = "q"
+//! ```
+
+use std::{
+    fmt::Display,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use color_eyre::{
+    eyre::{eyre, WrapErr},
+    Result,
+};
+use log::LevelFilter;
+use ratatui::{crossterm::event::KeyCode, style::Color};
+use serde::{de, Deserialize, Deserializer};
+use vlawn::Heartbeat;
+
+use crate::cli::Args;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Name to go by instead of the login name
+    pub name: Option<String>,
+    /// Host to join on startup, as `host[:port]`
+    pub host: Option<String>,
+    /// Room on the LAN to join once it's discovered, unless there's a host to join
+    pub room: Option<String>,
+    /// Port to take connections from other peers on
+    pub port: Option<u16>,
+    /// Address to take connections from other peers at, instead of every one
+    pub bind: Option<IpAddr>,
+    /// Address, or name of the network interface, other peers should reach us at
+    pub interface: Option<String>,
+    /// Read lines from stdin and print the room to stdout, instead of the terminal UI
+    pub headless: bool,
+    /// Encrypt links to other peers, which then need it on as well
+    pub tls: bool,
+    /// Seconds between pings to other peers
+    pub heartbeat_interval: Option<u64>,
+    /// Seconds other peers may stay silent before they're dropped
+    pub heartbeat_timeout: Option<u64>,
+    pub log: Log,
+    pub theme: Theme,
+    pub keys: Keys,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Log {
+    /// File to append to, instead of a new one in the working directory for every run
+    pub path: Option<PathBuf>,
+    #[serde(deserialize_with = "parse")]
+    pub level: LevelFilter,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            path: None,
+            level: LevelFilter::Info,
+        }
+    }
+}
+
+/// Colors of the terminal UI, as names, `#rrggbb` or indices into the terminal's palette
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    #[serde(deserialize_with = "color")]
+    pub foreground: Color,
+    #[serde(deserialize_with = "color")]
+    pub background: Color,
+    /// The grass along the top
+    #[serde(deserialize_with = "color")]
+    pub grass: Color,
+    /// The input box while typing
+    #[serde(deserialize_with = "color")]
+    pub typing: Color,
+    /// Joins, leaves and other notices in the timeline
+    #[serde(deserialize_with = "color")]
+    pub notice: Color,
+    #[serde(deserialize_with = "color")]
+    pub error: Color,
+    /// Timestamps, fingerprints and other details
+    #[serde(deserialize_with = "color")]
+    pub dim: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            foreground: Color::Indexed(229),
+            background: Color::Indexed(235),
+            grass: Color::Indexed(40),
+            typing: Color::LightGreen,
+            notice: Color::Cyan,
+            error: Color::Red,
+            dim: Color::DarkGray,
+        }
+    }
+}
+
+/// Keys of the terminal UI, as a character or a name like `enter` or `f1`
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Keys {
+    #[serde(deserialize_with = "key")]
+    pub up: KeyCode,
+    #[serde(deserialize_with = "key")]
+    pub down: KeyCode,
+    /// Join the room selected in the lobby
+    #[serde(deserialize_with = "key")]
+    pub join: KeyCode,
+    /// Start and stop typing
+    #[serde(deserialize_with = "key")]
+    pub typing: KeyCode,
+    /// Leave the room and exit
+    #[serde(deserialize_with = "key")]
+    pub quit: KeyCode,
+    /// Create an invite code for the room we lead
+    #[serde(deserialize_with = "key")]
+    pub invite: KeyCode,
+    #[serde(deserialize_with = "key")]
+    pub new_room: KeyCode,
+    #[serde(deserialize_with = "key")]
+    pub blind_room: KeyCode,
+    /// Create a room protected by the passphrase typed before
+    #[serde(deserialize_with = "key")]
+    pub protected_room: KeyCode,
+    #[serde(deserialize_with = "key")]
+    pub invite_only_room: KeyCode,
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys {
+            up: KeyCode::Up,
+            down: KeyCode::Down,
+            join: KeyCode::Enter,
+            typing: KeyCode::Tab,
+            quit: KeyCode::Esc,
+            invite: KeyCode::Char('i'),
+            new_room: KeyCode::Char('n'),
+            blind_room: KeyCode::Char('b'),
+            protected_room: KeyCode::Char('p'),
+            invite_only_room: KeyCode::Char('i'),
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file at `path`, or at the default location where it may be missing
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if !required && e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(e).wrap_err(format!("Could not read {}", path.display())),
+        };
+        let mut config: Config =
+            toml::from_str(&text).wrap_err(format!("Invalid config in {}", path.display()))?;
+        config.log.path = config.log.path.map(|path| expand_home(&path));
+        Ok(config)
+    }
+
+    /// Let the flags given on the command line win over the file
+    pub fn apply(&mut self, args: &Args) {
+        self.name = args.nick.clone().or(self.name.take());
+        self.port = args.port.or(self.port);
+        self.bind = args.bind.or(self.bind);
+        self.interface = args.interface.clone().or(self.interface.take());
+        self.log.path = args.log_file.clone().or(self.log.path.take());
+        self.log.level = args.log_level.unwrap_or(self.log.level);
+        self.headless |= args.headless;
+        self.tls |= args.tls;
+        self.heartbeat_interval = args.heartbeat_interval.or(self.heartbeat_interval);
+        self.heartbeat_timeout = args.heartbeat_timeout.or(self.heartbeat_timeout);
+    }
+
+    /// How often to ping other peers and how long to wait on them, where not the default
+    pub fn heartbeat(&self) -> Result<Heartbeat> {
+        let default = Heartbeat::default();
+        let heartbeat = Heartbeat {
+            interval: self
+                .heartbeat_interval
+                .map_or(default.interval, Duration::from_secs),
+            timeout: self
+                .heartbeat_timeout
+                .map_or(default.timeout, Duration::from_secs),
+        };
+        if heartbeat.interval.is_zero() || heartbeat.timeout <= heartbeat.interval {
+            return Err(eyre!(
+                "The heartbeat timeout ({:?}) must be longer than its interval ({:?}), which can't be 0",
+                heartbeat.timeout,
+                heartbeat.interval
+            ));
+        }
+        Ok(heartbeat)
+    }
+}
+
+/// Where the config file is read from unless told otherwise
+pub fn default_path() -> Option<PathBuf> {
+    vlawn::paths::config_dir().map(|dir| dir.join("config.toml"))
+}
+
+/// `path` with a leading `~` standing for the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), std::env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+fn parse<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    // Palette indices are fine as numbers rather than strings
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Index(u8),
+        Name(String),
+    }
+    match Raw::deserialize(deserializer)? {
+        Raw::Index(index) => Ok(Color::Indexed(index)),
+        Raw::Name(name) => name
+            .parse()
+            .map_err(|_| de::Error::custom(format!("unknown color {name}"))),
+    }
+}
+
+fn key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<KeyCode, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(c));
+    }
+    let code = match name.to_lowercase().as_str() {
+        "enter" | "return" => KeyCode::Enter,
+        "esc" | "escape" => KeyCode::Esc,
+        "tab" => KeyCode::Tab,
+        "backtab" => KeyCode::BackTab,
+        "space" => KeyCode::Char(' '),
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" => KeyCode::Insert,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        f => match f.strip_prefix('f').and_then(|n| n.parse().ok()) {
+            Some(n) => KeyCode::F(n),
+            None => return Err(de::Error::custom(format!("unknown key {name}"))),
+        },
+    };
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    const FILE: &str = r##"
+name = "ada"
+port = 57185
+bind = "192.168.1.20"
+interface = "eth0"
+heartbeat_timeout = 10
+
+[log]
+path = "~/vlawn.log"
+level = "debug"
+
+[theme]
+background = 235
+notice = "#5fafaf"
+
+[keys]
+quit = "q"
+join = "F1"
+"##;
+
+    fn load(text: &str) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!("vlawn-{:016x}.toml", rand::random::<u64>()));
+        fs::write(&path, text).unwrap();
+        let config = Config::load(Some(&path));
+        fs::remove_file(path).unwrap();
+        config
+    }
+
+    fn args(flags: &[&str]) -> Args {
+        Args::try_parse_from(std::iter::once("vlawn").chain(flags.iter().copied())).unwrap()
+    }
+
+    #[test]
+    fn reads_the_file() {
+        let config = load(FILE).unwrap();
+        assert_eq!(config.name.as_deref(), Some("ada"));
+        assert_eq!(config.port, Some(57185));
+        assert_eq!(config.bind, Some("192.168.1.20".parse().unwrap()));
+        assert_eq!(config.interface.as_deref(), Some("eth0"));
+        assert!(!config.headless && !config.tls);
+        let heartbeat = config.heartbeat().unwrap();
+        assert_eq!(heartbeat.interval, Heartbeat::default().interval);
+        assert_eq!(heartbeat.timeout, Duration::from_secs(10));
+        assert_eq!(config.log.level, LevelFilter::Debug);
+        assert!(!config.log.path.unwrap().starts_with("~"));
+        assert_eq!(config.theme.background, Color::Indexed(235));
+        assert_eq!(config.theme.notice, Color::Rgb(0x5f, 0xaf, 0xaf));
+        assert_eq!(config.theme.foreground, Theme::default().foreground);
+        assert_eq!(config.keys.quit, KeyCode::Char('q'));
+        assert_eq!(config.keys.join, KeyCode::F(1));
+        assert_eq!(config.keys.up, Keys::default().up);
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let mut config = load(FILE).unwrap();
+        config.apply(&args(&[
+            "--nick",
+            "grace",
+            "--port",
+            "4000",
+            "--bind",
+            "10.0.0.1",
+            "--log-level",
+            "warn",
+            "--headless",
+            "--tls",
+            "--heartbeat-interval",
+            "5",
+        ]));
+        assert_eq!(config.name.as_deref(), Some("grace"));
+        assert_eq!(config.port, Some(4000));
+        assert_eq!(config.bind, Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(config.log.level, LevelFilter::Warn);
+        assert!(config.headless && config.tls);
+        assert_eq!(config.heartbeat_interval, Some(5));
+        // What no flag was given for stays as configured
+        assert_eq!(config.interface.as_deref(), Some("eth0"));
+        assert!(config.log.path.is_some());
+        assert_eq!(config.heartbeat_timeout, Some(10));
+    }
+
+    #[test]
+    fn keeps_the_file_without_flags() {
+        let mut config = load("headless = true\ntls = true").unwrap();
+        config.apply(&args(&[]));
+        assert!(config.headless && config.tls);
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_values() {
+        assert!(load("nmae = \"ada\"").is_err());
+        assert!(load("[theme]\nnotice = \"not a color\"").is_err());
+        assert!(load("[keys]\nquit = \"not a key\"").is_err());
+        assert!(load("[log]\nlevel = \"loud\"").is_err());
+        assert!(load("heartbeat_interval = -1").is_err());
+    }
+
+    #[test]
+    fn rejects_heartbeats_that_expire_before_a_ping() {
+        let mut config = load("heartbeat_interval = 5\nheartbeat_timeout = 5").unwrap();
+        assert!(config.heartbeat().is_err());
+        config.apply(&args(&["--heartbeat-timeout", "15"]));
+        assert!(config.heartbeat().is_ok());
+        config.apply(&args(&["--heartbeat-interval", "0"]));
+        assert!(config.heartbeat().is_err());
+    }
+}
@@ -0,0 +1,421 @@
+//! End-to-end encryption of room traffic.
+//!
+//! Messages are sealed with a group key shared by the room's members. Whenever someone joins
+//! or leaves, the keeper, which is the first peer of the room's key directory, draws a new key
+//! for the next epoch and wraps it for the X25519 key of every member. The admin only relays
+//! wrapped keys, so it can't read the room unless it published a key of its own.
+//!
+//! Members sign their X25519 key with their identity, and the keeper signs the keys it hands
+//! out, so an admin relaying them can't slip in keys of its own.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs, io,
+    path::PathBuf,
+};
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, XChaCha20Poly1305};
+use ed25519_dalek::Signature;
+use hkdf::Hkdf;
+use postcard::{from_bytes, to_allocvec};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    entities::{ForwardPayload, Peer, PeerId},
+    identity::Identity,
+};
+
+/// Context for deriving the keys that wrap group keys
+const WRAP_INFO: &[u8] = b"vlawn group key wrap";
+/// Leads what's signed to vouch for an X25519 key
+const KEY_CONTEXT: &[u8] = b"vlawn x25519 key";
+/// Leads what's signed to hand out a group key
+const HANDOUT_CONTEXT: &[u8] = b"vlawn group key handout";
+
+/// Public X25519 key of a peer, as published in the room's key directory
+pub type PublicKeyBytes = [u8; 32];
+
+/// An X25519 key, signed by the identity of the peer it belongs to
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct SignedKey {
+    pub key: PublicKeyBytes,
+    signature: Signature,
+}
+
+impl SignedKey {
+    fn new(key: PublicKeyBytes) -> Self {
+        SignedKey {
+            key,
+            signature: Identity::local().sign(&[KEY_CONTEXT, &key].concat()),
+        }
+    }
+
+    /// Whether the key belongs to `owner`
+    pub fn verify(&self, owner: &PeerId) -> bool {
+        owner.verify(&[KEY_CONTEXT, &self.key].concat(), &self.signature)
+    }
+}
+
+/// A [`ForwardPayload`] encrypted with the group key of the given epoch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sealed {
+    pub epoch: u64,
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+/// A group key encrypted for a single member
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WrappedKey {
+    /// Public half of the one-off key the wrapping key was agreed with
+    ephemeral: PublicKeyBytes,
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+/// The group key of an epoch, wrapped for each member and signed by the keeper
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyHandout {
+    pub epoch: u64,
+    wrapped: Vec<(Peer, WrappedKey)>,
+    signature: Signature,
+}
+
+impl KeyHandout {
+    fn new(epoch: u64, wrapped: Vec<(Peer, WrappedKey)>) -> Self {
+        let signature = Identity::local().sign(&handout_bytes(epoch, &wrapped));
+        KeyHandout {
+            epoch,
+            wrapped,
+            signature,
+        }
+    }
+
+    /// Whether `keeper` handed this out
+    pub fn verify(&self, keeper: &PeerId) -> bool {
+        keeper.verify(&handout_bytes(self.epoch, &self.wrapped), &self.signature)
+    }
+}
+
+fn handout_bytes(epoch: u64, wrapped: &[(Peer, WrappedKey)]) -> Vec<u8> {
+    let wrapped: Vec<(&PeerId, &WrappedKey)> = wrapped.iter().map(|(p, w)| (p.id(), w)).collect();
+    [HANDOUT_CONTEXT, &to_allocvec(&(epoch, wrapped)).unwrap()].concat()
+}
+
+/// Symmetric key messages of one epoch are sealed with
+#[derive(Clone)]
+struct GroupKey([u8; 32]);
+
+impl GroupKey {
+    fn random() -> Self {
+        GroupKey(rand::random())
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+
+    fn seal(&self, epoch: u64, payload: &ForwardPayload) -> Sealed {
+        let nonce: [u8; 24] = rand::random();
+        let plaintext = to_allocvec(payload).unwrap();
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce.into(), plaintext.as_slice())
+            .unwrap();
+        Sealed {
+            epoch,
+            nonce,
+            ciphertext,
+        }
+    }
+
+    fn open(&self, sealed: &Sealed) -> Option<ForwardPayload> {
+        let plaintext = self
+            .cipher()
+            .decrypt(&sealed.nonce.into(), sealed.ciphertext.as_slice())
+            .ok()?;
+        from_bytes(&plaintext).ok()
+    }
+}
+
+impl fmt::Debug for GroupKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("GroupKey(..)")
+    }
+}
+
+/// Cipher for wrapping a group key, from the agreement between `ephemeral` and `recipient`
+fn wrapping_cipher(
+    shared: &[u8; 32],
+    ephemeral: &PublicKeyBytes,
+    recipient: &PublicKeyBytes,
+) -> ChaCha20Poly1305 {
+    let salt = [ephemeral.as_slice(), recipient.as_slice()].concat();
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .unwrap();
+    ChaCha20Poly1305::new(&key.into())
+}
+
+fn wrap(key: &GroupKey, recipient: &PublicKeyBytes) -> WrappedKey {
+    let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+    let ephemeral = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(*recipient));
+
+    let nonce: [u8; 12] = rand::random();
+    let ciphertext = wrapping_cipher(shared.as_bytes(), &ephemeral, recipient)
+        .encrypt(&nonce.into(), key.0.as_slice())
+        .unwrap();
+    WrappedKey {
+        ephemeral,
+        nonce,
+        ciphertext,
+    }
+}
+
+/// Our key pair, and the group keys of the room we're in
+pub struct Keyring {
+    secret: StaticSecret,
+    public: PublicKeyBytes,
+    room_id: Option<u64>,
+    epochs: BTreeMap<u64, GroupKey>,
+    /// Who hands out group keys, as of the latest directory
+    keeper: Option<PeerId>,
+    /// Members the latest group key was handed to, while we're the keeper
+    recipients: HashSet<Peer>,
+}
+
+impl Keyring {
+    /// A keyring with a fresh key pair, which lives as long as the process
+    pub fn new() -> Self {
+        let secret = StaticSecret::from(rand::random::<[u8; 32]>());
+        let public = PublicKey::from(&secret).to_bytes();
+        Keyring {
+            secret,
+            public,
+            room_id: None,
+            epochs: BTreeMap::new(),
+            keeper: None,
+            recipients: HashSet::new(),
+        }
+    }
+
+    /// Our public key, signed for publishing in the directory
+    pub fn public(&self) -> SignedKey {
+        SignedKey::new(self.public)
+    }
+
+    /// Switch to the keys of the room `room_id`, loading what earlier sessions stored
+    pub fn attach(&mut self, room_id: u64) {
+        if self.room_id == Some(room_id) {
+            return;
+        }
+        self.room_id = Some(room_id);
+        self.epochs.clear();
+        self.keeper = None;
+        self.recipients.clear();
+
+        match load_keys(room_id) {
+            Ok(keys) => self
+                .epochs
+                .extend(keys.into_iter().map(|(epoch, key)| (epoch, GroupKey(key)))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Failed to load room keys: {e}"),
+        }
+    }
+
+    pub fn has_key(&self) -> bool {
+        !self.epochs.is_empty()
+    }
+
+    /// Seal `payload` with the latest group key, unless we don't have one yet
+    pub fn seal(&self, payload: &ForwardPayload) -> Option<ForwardPayload> {
+        let (epoch, key) = self.epochs.last_key_value()?;
+        Some(ForwardPayload::Sealed(key.seal(*epoch, payload)))
+    }
+
+    /// Contents of `payload`, unless it's sealed with a key we weren't given
+    pub fn open(&self, payload: &ForwardPayload) -> Option<ForwardPayload> {
+        match payload {
+            ForwardPayload::Sealed(sealed) => self.epochs.get(&sealed.epoch)?.open(sealed),
+            payload => Some(payload.clone()),
+        }
+    }
+
+    /// Draw a new group key for `directory` if we're its keeper and its members changed,
+    /// returning the key wrapped for each of them whose key is signed by them
+    pub fn rekey(&mut self, me: &Peer, directory: &[(Peer, SignedKey)]) -> Option<KeyHandout> {
+        self.keeper = directory.first().map(|(keeper, _)| *keeper.id());
+        if self.keeper.as_ref() != Some(me.id()) {
+            self.recipients.clear();
+            return None;
+        }
+        let directory: Vec<&(Peer, SignedKey)> = directory
+            .iter()
+            .filter(|(peer, key)| {
+                let valid = key.verify(peer.id());
+                if !valid {
+                    log::warn!("Not handing the group key to {peer:?}, whose key isn't theirs");
+                }
+                valid
+            })
+            .collect();
+        let recipients: HashSet<Peer> = directory.iter().map(|(p, _)| p.clone()).collect();
+        if recipients == self.recipients {
+            return None;
+        }
+
+        let epoch = self.epochs.last_key_value().map_or(1, |(e, _)| e + 1);
+        log::info!(
+            "Rekeying room for {} members, epoch {epoch}",
+            recipients.len()
+        );
+        let key = GroupKey::random();
+        let wrapped = directory
+            .iter()
+            .map(|(peer, public)| (peer.clone(), wrap(&key, &public.key)))
+            .collect();
+        self.recipients = recipients;
+        Some(KeyHandout::new(epoch, wrapped))
+    }
+
+    /// Take the group key that was wrapped for us in `handout`, if any and if the keeper
+    /// handed it out
+    pub fn accept(&mut self, me: &Peer, handout: &KeyHandout) {
+        let epoch = handout.epoch;
+        if !self.keeper.is_some_and(|keeper| handout.verify(&keeper)) {
+            log::warn!("Ignoring group key for epoch {epoch}, which the keeper didn't sign");
+            return;
+        }
+        let Some((_, wrapped)) = handout.wrapped.iter().find(|(peer, _)| peer == me) else {
+            return;
+        };
+        let shared = self
+            .secret
+            .diffie_hellman(&PublicKey::from(wrapped.ephemeral));
+        let key = wrapping_cipher(shared.as_bytes(), &wrapped.ephemeral, &self.public)
+            .decrypt(&wrapped.nonce.into(), wrapped.ciphertext.as_slice());
+        let Ok(Ok(key)) = key.map(<[u8; 32]>::try_from) else {
+            log::warn!("Could not unwrap group key for epoch {epoch}");
+            return;
+        };
+
+        self.epochs.insert(epoch, GroupKey(key));
+        if let Some(room_id) = self.room_id {
+            if let Err(e) = self.save(room_id) {
+                log::warn!("Failed to store room keys: {e}");
+            }
+        }
+    }
+
+    fn save(&self, room_id: u64) -> io::Result<()> {
+        let keys: Vec<(u64, [u8; 32])> = self.epochs.iter().map(|(e, k)| (*e, k.0)).collect();
+        let bytes = to_allocvec(&keys).map_err(io::Error::other)?;
+
+        let path = keys_path(room_id)?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+        }
+        fs::rename(tmp, path)
+    }
+}
+
+fn keys_path(room_id: u64) -> io::Result<PathBuf> {
+    Ok(crate::store::room_dir(room_id)?.join("keys"))
+}
+
+fn load_keys(room_id: u64) -> io::Result<Vec<(u64, [u8; 32])>> {
+    let bytes = fs::read(keys_path(room_id)?)?;
+    from_bytes(&bytes).map_err(io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str) -> ForwardPayload {
+        ForwardPayload::Text(text.to_string())
+    }
+
+    /// Us, holding the group key we handed ourselves as the keeper
+    fn keeper() -> (Peer, Keyring, KeyHandout) {
+        // Signing with the stored identity would create it in the data directory
+        let _ = Identity::use_temporary();
+        let me = Peer::get_local();
+        let mut keyring = Keyring::new();
+        let directory = [(me.clone(), keyring.public())];
+        let handout = keyring.rekey(&me, &directory).unwrap();
+        keyring.accept(&me, &handout);
+        (me, keyring, handout)
+    }
+
+    #[test]
+    fn seals_and_opens() {
+        let (_, keyring, _) = keeper();
+        let sealed = keyring.seal(&text("hello")).unwrap();
+        assert!(matches!(sealed, ForwardPayload::Sealed(_)));
+        assert_eq!(keyring.open(&sealed), Some(text("hello")));
+    }
+
+    #[test]
+    fn leaves_unsealed_payloads_alone() {
+        assert_eq!(Keyring::new().open(&text("hello")), Some(text("hello")));
+    }
+
+    #[test]
+    fn seals_nothing_without_a_key() {
+        assert!(Keyring::new().seal(&text("hello")).is_none());
+    }
+
+    #[test]
+    fn does_not_open_with_the_wrong_key() {
+        let (_, keyring, _) = keeper();
+        let ForwardPayload::Sealed(sealed) = keyring.seal(&text("hello")).unwrap() else {
+            unreachable!();
+        };
+        assert!(GroupKey::random().open(&sealed).is_none());
+        // Nor when the ciphertext was tampered with
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert!(keyring.open(&ForwardPayload::Sealed(tampered)).is_none());
+    }
+
+    #[test]
+    fn does_not_unwrap_a_key_wrapped_for_someone_else() {
+        let (me, keyring, handout) = keeper();
+        let mut other = Keyring::new();
+        other.keeper = Some(*me.id());
+        other.accept(&me, &handout);
+        assert!(!other.has_key());
+        let sealed = keyring.seal(&text("hello")).unwrap();
+        assert!(other.open(&sealed).is_none());
+    }
+
+    #[test]
+    fn ignores_a_handout_the_keeper_did_not_sign() {
+        let (me, _, handout) = keeper();
+        let mut other = Keyring::new();
+        other.keeper = Some(PeerId([9; 32]));
+        other.accept(&me, &handout);
+        assert!(!other.has_key());
+    }
+
+    #[test]
+    fn hands_nothing_to_keys_not_signed_by_their_owner() {
+        let _ = Identity::use_temporary();
+        let me = Peer::get_local();
+        let mut keyring = Keyring::new();
+        let forged = SignedKey {
+            key: [1; 32],
+            ..keyring.public()
+        };
+        assert!(keyring.rekey(&me, &[(me.clone(), forged)]).is_none());
+    }
+}
@@ -0,0 +1,107 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use ed25519_dalek::Signature;
+use serde::{Deserialize, Serialize};
+
+use crate::identity::Identity;
+
+use super::{Peer, Room};
+
+/// Multicast group admins announce their rooms on
+pub const BEACON_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 71, 85);
+/// Link-local multicast group admins announce their rooms on over IPv6
+pub const BEACON_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0x7185, 0x55);
+pub const BEACON_PORT: u16 = 57186;
+
+pub fn beacon_addr() -> SocketAddrV4 {
+    SocketAddrV4::new(BEACON_GROUP, BEACON_PORT)
+}
+
+/// Where to announce rooms over IPv6 on the interface with index `scope_id`
+pub fn beacon_addr_v6(scope_id: u32) -> SocketAddrV6 {
+    SocketAddrV6::new(BEACON_GROUP_V6, BEACON_PORT, 0, scope_id)
+}
+
+/// Periodic announcement of a room, sent by its admin over UDP multicast
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Beacon {
+    /// Identity of the room, shared by every admin it has had
+    pub id: u64,
+    pub name: String,
+    pub admin: Peer,
+    pub members: u32,
+    pub term: u64,
+    /// Made by the admin over everything else, so nobody can announce the room in its name
+    signature: Signature,
+}
+
+impl Beacon {
+    /// Announcement of `room`, which we lead, signed with our identity
+    pub fn from_room(room: &Room) -> Self {
+        Self::signed_by(Identity::local(), room)
+    }
+
+    /// Announcement of `room` by the holder of `identity`, which leads it
+    pub(crate) fn signed_by(identity: &Identity, room: &Room) -> Self {
+        let admin = room
+            .hierarchy()
+            .admin()
+            .cloned()
+            .unwrap_or_else(Peer::get_local);
+        let (id, name, members, term) = (
+            room.id(),
+            room.name().clone(),
+            room.hierarchy().0.len() as u32,
+            room.term(),
+        );
+        let signature = identity.sign(&signed_bytes(id, &name, &admin, members, term));
+        Self {
+            id,
+            name,
+            admin,
+            members,
+            term,
+            signature,
+        }
+    }
+
+    /// Whether the beacon was signed by the admin it names, and hasn't been tampered with
+    pub fn verify(&self) -> bool {
+        let signed = signed_bytes(self.id, &self.name, &self.admin, self.members, self.term);
+        self.admin.id().verify(&signed, &self.signature)
+    }
+
+    /// Whether this admin should win over `other` when both lead the same room.
+    ///
+    /// Both sides of a split evaluate this identically, so exactly one of them yields. Admins
+    /// differ by id at the very least, so the two never tie.
+    pub fn outranks(&self, other: &Beacon) -> bool {
+        (
+            self.term,
+            self.members,
+            self.admin.addr(),
+            self.admin.username(),
+            &self.admin.id().0,
+        ) > (
+            other.term,
+            other.members,
+            other.admin.addr(),
+            other.admin.username(),
+            &other.admin.id().0,
+        )
+    }
+}
+
+fn signed_bytes(id: u64, name: &str, admin: &Peer, members: u32, term: u64) -> Vec<u8> {
+    postcard::to_allocvec(&("vlawn beacon", id, name, admin, members, term)).unwrap()
+}
+
+/// Everything exchanged over the discovery socket
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Datagram {
+    Beacon(Beacon),
+    /// Latency probe sent by a discovering peer to an admin's announcer
+    Ping(u64),
+    /// Echo of a [`Datagram::Ping`] nonce
+    Pong(u64),
+}
@@ -0,0 +1,33 @@
+use std::io;
+
+use thiserror::Error;
+
+/// What can go wrong talking to other peers
+#[derive(Debug, Error)]
+pub enum VlawnError {
+    #[error("malformed message: {0}")]
+    Malformed(#[from] postcard::Error),
+    #[error("expected a binary frame, got text")]
+    TextFrame,
+    #[error("incompatible peer: {0}")]
+    Incompatible(String),
+    #[error("connection failed: {0}")]
+    Connection(Box<ws::Error>),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// The state machine stopped listening for events, which happens while shutting down
+    #[error("event loop is gone")]
+    Disconnected,
+}
+
+impl From<ws::Error> for VlawnError {
+    fn from(e: ws::Error) -> Self {
+        VlawnError::Connection(Box::new(e))
+    }
+}
+
+impl<T> From<crossbeam_channel::SendError<T>> for VlawnError {
+    fn from(_: crossbeam_channel::SendError<T>) -> Self {
+        VlawnError::Disconnected
+    }
+}
@@ -1,10 +1,13 @@
-use std::net::IpAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use ws::Sender;
 
 use crate::entities::ForwardPayload;
 
-use super::Message;
+use super::{Beacon, Endpoint, Message, RoomOptions, Sanction};
 
 #[derive(Debug, Clone)]
 pub enum Event {
@@ -13,9 +16,37 @@ pub enum Event {
 
     Message(Message, u32),
 
-    Open(Sender),
-    JoinSend(IpAddr),
+    /// A peer connected to our server from the given address, if known
+    Open(Sender, u32, Option<IpAddr>),
+    /// A connection we dialed to the given endpoint is open
+    Connected(Sender, u32, Endpoint),
+    /// Dialing the given endpoint failed
+    ConnectFailed(Endpoint),
+    JoinSend(Endpoint),
     SubmitMessage(ForwardPayload),
 
-    StartRoom,
+    StartRoom(RoomOptions),
+    /// Leave the current room and shut down, handing the room over first if we're its admin
+    Leave,
+    /// Leave the current room for the lobby
+    Part,
+    /// Go by the given name from now on
+    Rename(String),
+    /// Set the topic of the room we're in
+    SetTopic(String),
+    /// Passphrase or invite code for the protected room we're joining
+    Secret(String),
+    /// Create a one-time invite code for the room we lead
+    CreateInvite,
+    /// Apply a sanction to the peer with the given username or fingerprint
+    Moderate(Sanction, String),
+
+    /// Start listening for rooms announced on the LAN
+    Discover,
+    /// A room announcement was received from the given address
+    Beacon(Beacon, SocketAddr),
+    /// Round trip time to the announcer at the given address
+    Latency(IpAddr, Duration),
+    /// Something failed outside of any connection, to be shown to the user
+    Error(String),
 }
@@ -1,41 +1,386 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
 use crossbeam_channel::Sender;
-use postcard::from_bytes;
-use ws::{Handler as WsHandler, Message as WsMessage, Result};
+use openssl::ssl::SslStream;
+use postcard::{from_bytes, take_from_bytes, to_allocvec};
+use ws::{
+    util::{TcpStream, Timeout, Token},
+    CloseCode, Frame, Handler as WsHandler, Handshake, Message as WsMessage, Request, Response,
+    Result, Sender as WsSender,
+};
+
+use crate::tls::Tls;
+
+use super::{Endpoint, Event, Header, Message, Payload, Routes, VlawnError};
+
+static NEXT_CONNECTION_ID: AtomicU32 = AtomicU32::new(0);
+
+const PING: Token = Token(1);
+const EXPIRE: Token = Token(2);
 
-use super::{Event, Message};
+/// Allocate an id that is unique across every websocket we listen on or dial out from.
+///
+/// `ws::Sender::connection_id` is only unique within a single `ws::WebSocket`, and each
+/// outgoing `ws::connect` creates a new one.
+pub fn next_connection_id() -> u32 {
+    NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// How often peers are pinged, and how long they may stay silent before being dropped
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(6),
+        }
+    }
+}
 
 pub struct Handler {
     events_tx: Sender<Event>,
     connection_id: u32,
+    sender: WsSender,
+    heartbeat: Heartbeat,
+    /// Pending expiry of the connection, pushed back whenever a frame arrives
+    expire: Option<Timeout>,
+    /// Whether the connection is open and its closing hasn't been reported yet
+    open: bool,
+    outgoing: Option<Outgoing>,
+    /// Rooms sharing the port a connection we accepted came in on, which it picks from
+    routes: Option<Routes>,
+    /// Encrypts the connection, when it was made over `wss://`
+    tls: Option<Arc<Tls>>,
+}
+
+/// State of a connection we dialed ourselves
+struct Outgoing {
+    endpoint: Endpoint,
+    /// First message to send once the handshake completes
+    greeting: Vec<u8>,
+    opened: Arc<AtomicBool>,
 }
 
 impl Handler {
-    pub fn new(events_tx: Sender<Event>, connection_id: u32) -> Self {
+    pub fn new(
+        events_tx: Sender<Event>,
+        connection_id: u32,
+        sender: WsSender,
+        heartbeat: Heartbeat,
+    ) -> Self {
         Handler {
             events_tx,
             connection_id,
+            sender,
+            heartbeat,
+            expire: None,
+            open: false,
+            outgoing: None,
+            routes: None,
+            tls: None,
+        }
+    }
+
+    /// Handler for a connection accepted on a port shared by `routes`, whose events go to
+    /// the room its request picks
+    pub(crate) fn incoming(
+        routes: Routes,
+        connection_id: u32,
+        sender: WsSender,
+        heartbeat: Heartbeat,
+    ) -> Self {
+        // Nothing is emitted before the request is routed
+        let (events_tx, _) = crossbeam_channel::bounded(0);
+        Handler {
+            routes: Some(routes),
+            ..Handler::new(events_tx, connection_id, sender, heartbeat)
+        }
+    }
+
+    pub fn with_tls(self, tls: Option<Arc<Tls>>) -> Self {
+        Handler { tls, ..self }
+    }
+
+    /// Handler for a connection to `endpoint`, which sends `greeting` and reports
+    /// [`Event::Connected`] once open, and sets `opened`.
+    pub fn outgoing(
+        events_tx: Sender<Event>,
+        connection_id: u32,
+        sender: WsSender,
+        heartbeat: Heartbeat,
+        endpoint: Endpoint,
+        greeting: Vec<u8>,
+        opened: Arc<AtomicBool>,
+    ) -> Self {
+        Handler {
+            outgoing: Some(Outgoing {
+                endpoint,
+                greeting,
+                opened,
+            }),
+            ..Handler::new(events_tx, connection_id, sender, heartbeat)
+        }
+    }
+
+    /// Hand `event` to the state machine, closing the connection if nobody listens anymore
+    fn emit(&self, event: Event) {
+        if let Err(e) = self.events_tx.send(event).map_err(VlawnError::from) {
+            log::warn!("Closing connection {}: {e}", self.connection_id);
+            let _ = self.sender.close(CloseCode::Away);
+        }
+    }
+
+    /// Report the connection as closed, at most once
+    fn report_closed(&mut self) {
+        if let Some(tls) = &self.tls {
+            tls.forget(self.connection_id);
+        }
+        if std::mem::take(&mut self.open) {
+            self.emit(Event::Closed(self.connection_id));
         }
     }
 }
 
+/// Decode a frame, checking that its sender speaks a compatible protocol first
+fn decode(ws_msg: WsMessage) -> std::result::Result<Message, VlawnError> {
+    let WsMessage::Binary(bin) = ws_msg else {
+        return Err(VlawnError::TextFrame);
+    };
+    let (header, _) = take_from_bytes::<Header>(&bin)?;
+    if let Some(reason) = header.incompatibility() {
+        return Err(VlawnError::Incompatible(reason));
+    }
+    // A compatible peer may still use payloads newer than ours
+    Ok(from_bytes(&bin)?)
+}
+
 impl WsHandler for Handler {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        if let Some(routes) = &self.routes {
+            match routes.route(req.resource()) {
+                Some(events_tx) => self.events_tx = events_tx,
+                None => {
+                    log::warn!(
+                        "Connection {} asked for {}, which no room is at",
+                        self.connection_id,
+                        req.resource()
+                    );
+                    return Ok(Response::new(404, "Not Found", b"No such room".to_vec()));
+                }
+            }
+        }
+        Response::from_request(req)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.open = true;
+        if let Some(outgoing) = &self.outgoing {
+            outgoing.opened.store(true, Ordering::Relaxed);
+            self.sender.send(outgoing.greeting.clone())?;
+            self.emit(Event::Connected(
+                self.sender.clone(),
+                self.connection_id,
+                outgoing.endpoint,
+            ));
+        } else {
+            self.emit(Event::Open(
+                self.sender.clone(),
+                self.connection_id,
+                // Listening on IPv6 also accepts IPv4 peers, mapped into IPv6
+                shake.peer_addr.map(|addr| addr.ip().to_canonical()),
+            ));
+        }
+
+        self.sender
+            .timeout(self.heartbeat.interval.as_millis() as u64, PING)?;
+        self.sender
+            .timeout(self.heartbeat.timeout.as_millis() as u64, EXPIRE)
+    }
+
     fn on_message(&mut self, ws_msg: WsMessage) -> Result<()> {
-        let WsMessage::Binary(bin) = ws_msg else {
-            unimplemented!("Expected binary message");
+        let msg = match decode(ws_msg) {
+            Ok(msg) => msg,
+            Err(VlawnError::Incompatible(reason)) => {
+                log::warn!("Closing connection {}: {reason}", self.connection_id);
+                if self.outgoing.is_some() {
+                    // Let the state machine know why the peer we dialed can't be joined
+                    let msg = Message::new(Payload::Reject(reason));
+                    self.emit(Event::Message(msg, self.connection_id));
+                } else if let Ok(reject) = to_allocvec(&Message::new(Payload::Reject(reason))) {
+                    self.sender.send(reject)?;
+                }
+                return self.sender.close(CloseCode::Protocol);
+            }
+            Err(e) => {
+                log::warn!("Ignoring message on {}: {e}", self.connection_id);
+                return Ok(());
+            }
         };
-
-        let msg: Message = from_bytes(&bin).unwrap();
         log::info!("Received message: {msg:?}");
 
-        self.events_tx
-            .send(Event::Message(msg, self.connection_id))
-            .unwrap();
+        self.emit(Event::Message(msg, self.connection_id));
         Ok(())
     }
 
-    fn on_close(&mut self, _: ws::CloseCode, reason: &str) {
-        self.events_tx
-            .send(Event::Closed(self.connection_id))
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        match event {
+            PING => {
+                self.sender.ping(Vec::new())?;
+                self.sender
+                    .timeout(self.heartbeat.interval.as_millis() as u64, PING)
+            }
+            EXPIRE => {
+                log::warn!(
+                    "Connection {} missed heartbeats for {:?}, dropping it",
+                    self.connection_id,
+                    self.heartbeat.timeout
+                );
+                self.report_closed();
+                self.sender.close(CloseCode::Away)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        if event == EXPIRE {
+            if let Some(previous) = self.expire.replace(timeout) {
+                self.sender.cancel(previous)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        // Any frame, pongs included, shows the peer is still alive
+        self.sender
+            .timeout(self.heartbeat.timeout.as_millis() as u64, EXPIRE)?;
+        Ok(Some(frame))
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        log::info!(
+            "Connection {} closed ({code:?}): {reason}",
+            self.connection_id
+        );
+        self.report_closed();
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        log::warn!("Connection {} errored: {err}", self.connection_id);
+        self.report_closed();
+    }
+
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> Result<SslStream<TcpStream>> {
+        match &self.tls {
+            Some(tls) => Ok(tls.accept(sock)?),
+            None => Err(ws::Error::new(
+                ws::ErrorKind::Internal,
+                "TLS is not enabled",
+            )),
+        }
+    }
+
+    fn upgrade_ssl_client(
+        &mut self,
+        sock: TcpStream,
+        _url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        match (&self.tls, &self.outgoing) {
+            (Some(tls), Some(_)) => Ok(tls.connect(sock, self.connection_id)?),
+            _ => Err(ws::Error::new(
+                ws::ErrorKind::Internal,
+                "TLS is not enabled",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{IpAddr, Ipv4Addr, TcpStream as StdTcpStream},
+        time::Instant,
+    };
+
+    use crossbeam_channel::Receiver;
+
+    use super::*;
+    use crate::Switchboard;
+
+    const HEARTBEAT: Heartbeat = Heartbeat {
+        interval: Duration::from_millis(50),
+        timeout: Duration::from_millis(300),
+    };
+
+    /// A raw connection to a fresh server, past the opening handshake
+    fn connect() -> (StdTcpStream, Receiver<Event>) {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let switchboard = Switchboard::listen(Some(localhost), 0, HEARTBEAT, None).unwrap();
+        let (events_tx, events_rx) = crossbeam_channel::unbounded();
+        switchboard.attach(events_tx);
+
+        let mut stream = StdTcpStream::connect((localhost, switchboard.port())).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
             .unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        assert!(response.starts_with(b"HTTP/1.1 101"));
+        assert!(matches!(
+            events_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Event::Open(..))
+        ));
+        (stream, events_rx)
+    }
+
+    #[test]
+    fn pings_and_drops_silent_peers() {
+        let (mut stream, events_rx) = connect();
+        let opened = Instant::now();
+
+        // An empty ping, unmasked as it comes from the server
+        let mut frame = [0; 2];
+        stream.read_exact(&mut frame).unwrap();
+        assert_eq!(frame, [0x89, 0x00]);
+
+        match events_rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Event::Closed(_)) => assert!(opened.elapsed() >= HEARTBEAT.timeout),
+            other => panic!("Expected the connection to expire, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn keeps_peers_that_answer() {
+        let (mut stream, events_rx) = connect();
+        let deadline = Instant::now() + HEARTBEAT.timeout * 3;
+        while Instant::now() < deadline {
+            // A masked, empty pong, as clients have to mask their frames
+            stream.write_all(&[0x8a, 0x80, 0, 0, 0, 0]).unwrap();
+            std::thread::sleep(HEARTBEAT.interval);
+            assert!(events_rx.try_recv().is_err());
+        }
     }
 }
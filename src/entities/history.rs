@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use crate::store::RoomStore;
+
+use super::{Envelope, ForwardPayload, MessageId, Room};
+
+/// Something that happened on our end, shown among the room's messages but never sent
+#[derive(Debug, Clone)]
+pub struct Notice {
+    /// Milliseconds since the unix epoch
+    pub at: i64,
+    pub text: String,
+    pub is_error: bool,
+}
+
+/// A line added to the room's timeline, as told to subscribers
+#[derive(Debug, Clone)]
+pub enum Update {
+    /// A message, with its contents unless they're sealed with a key we weren't given
+    Message(Box<Envelope>, Option<ForwardPayload>),
+    Notice(Notice),
+}
+
+/// A line of the room's timeline
+pub enum Entry<'a> {
+    Message(&'a Envelope),
+    Notice(&'a Notice),
+}
+
+/// Messages of the room we're in, ordered by sequence number and mirrored to disk
+#[derive(Default)]
+pub struct History {
+    entries: Vec<Envelope>,
+    store: Option<RoomStore>,
+    /// What happened on our end while in the room, which is only kept in memory
+    notices: Vec<Notice>,
+    /// Lines added since subscribers were last told, with messages yet to be opened
+    fresh: Vec<Update>,
+}
+
+impl History {
+    pub fn entries(&self) -> &[Envelope] {
+        &self.entries
+    }
+
+    /// Messages interleaved with our notices by time
+    pub fn timeline(&self) -> Vec<Entry<'_>> {
+        let mut timeline = Vec::with_capacity(self.entries.len() + self.notices.len());
+        let mut notices = self.notices.iter().peekable();
+        for envelope in &self.entries {
+            while let Some(notice) = notices.next_if(|n| n.at < envelope.sent_at) {
+                timeline.push(Entry::Notice(notice));
+            }
+            timeline.push(Entry::Message(envelope));
+        }
+        timeline.extend(notices.map(Entry::Notice));
+        timeline
+    }
+
+    /// Note something that happened on our end
+    pub fn note(&mut self, text: String) {
+        self.push_notice(text, false);
+    }
+
+    /// Note something that went wrong on our end
+    pub fn error(&mut self, text: String) {
+        self.push_notice(text, true);
+    }
+
+    fn push_notice(&mut self, text: String, is_error: bool) {
+        let notice = Notice {
+            at: chrono::Utc::now().timestamp_millis(),
+            text,
+            is_error,
+        };
+        self.fresh.push(Update::Notice(notice.clone()));
+        self.notices.push(notice);
+    }
+
+    /// Lines added since the last call, leaving messages for the caller to open
+    pub fn take_fresh(&mut self) -> Vec<Update> {
+        std::mem::take(&mut self.fresh)
+    }
+
+    /// Switch to the history of `room`, loading what earlier sessions stored
+    pub fn attach(&mut self, room: &Room) {
+        if self
+            .store
+            .as_ref()
+            .is_some_and(|s| s.room_id() == room.id())
+        {
+            return;
+        }
+        self.entries.clear();
+        self.notices.clear();
+        self.store = None;
+
+        let store = match RoomStore::open(room.id(), room.name()) {
+            Ok(store) => store,
+            Err(e) => {
+                log::warn!("Chat history won't be saved: {e}");
+                return;
+            }
+        };
+        match store.load() {
+            Ok(envelopes) => {
+                log::info!("Loaded {} stored messages", envelopes.len());
+                envelopes.into_iter().for_each(|e| {
+                    self.insert_entry(e);
+                });
+            }
+            Err(e) => log::warn!("Failed to load stored history: {e}"),
+        }
+        self.store = Some(store);
+    }
+
+    /// Insert `envelope` in order and store it, unless a message with its id is already here.
+    ///
+    /// Returns whether it was inserted.
+    pub fn insert(&mut self, envelope: Envelope) -> bool {
+        if self.entries.iter().any(|e| e.id == envelope.id) {
+            return false;
+        }
+        if let Some(store) = &mut self.store {
+            if let Err(e) = store.append(&envelope) {
+                log::warn!("Failed to store message: {e}");
+            }
+        }
+        let fresh = Update::Message(Box::new(envelope.clone()), None);
+        let inserted = self.insert_entry(envelope);
+        if inserted {
+            self.fresh.push(fresh);
+        }
+        inserted
+    }
+
+    fn insert_entry(&mut self, envelope: Envelope) -> bool {
+        if self.entries.iter().any(|e| e.id == envelope.id) {
+            return false;
+        }
+        let key = envelope.order_key();
+        let index = self.entries.partition_point(|e| e.order_key() <= key);
+        self.entries.insert(index, envelope);
+        true
+    }
+
+    /// Ids of the messages we have
+    pub fn ids(&self) -> Vec<MessageId> {
+        self.entries.iter().map(|e| e.id).collect()
+    }
+
+    /// Messages we have besides those with the given ids
+    pub fn besides(&self, ids: &HashSet<MessageId>) -> Vec<Envelope> {
+        self.entries
+            .iter()
+            .filter(|e| !ids.contains(&e.id))
+            .cloned()
+            .collect()
+    }
+
+    /// Of the given ids, those we have no message for
+    pub fn lacking(&self, ids: &HashSet<MessageId>) -> Vec<MessageId> {
+        let held: HashSet<MessageId> = self.entries.iter().map(|e| e.id).collect();
+        ids.difference(&held).copied().collect()
+    }
+
+    /// Messages we have with the given ids
+    pub fn with_ids(&self, ids: &[MessageId]) -> Vec<Envelope> {
+        self.entries
+            .iter()
+            .filter(|e| ids.contains(&e.id))
+            .cloned()
+            .collect()
+    }
+
+    /// Sequence number of the newest message we have
+    pub fn last_seq(&self) -> Option<u64> {
+        self.entries.iter().filter_map(|e| e.seq).max()
+    }
+
+    /// Sequence number the admin assigns to the next message
+    pub fn next_seq(&self) -> u64 {
+        self.last_seq().map_or(0, |seq| seq + 1)
+    }
+}
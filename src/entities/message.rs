@@ -1,27 +1,343 @@
+use std::fmt;
+
+use ed25519_dalek::Signature;
 use serde::{Deserialize, Serialize};
 
-use super::{Peer, Room};
+use crate::{
+    access::Nonce,
+    crypto::{KeyHandout, Sealed, SignedKey},
+    identity::Identity,
+};
+
+use super::{Moderation, Peer, PeerId, Room};
+
+/// Version of the wire protocol spoken by this build
+pub const PROTOCOL_VERSION: u16 = 3;
+/// Oldest protocol version this build can still talk to; v3 peers carry their port
+pub const MIN_PROTOCOL_VERSION: u16 = 3;
+
+/// Leads every message, so peers can check compatibility before decoding the payload
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub min_version: u16,
+}
+
+impl Header {
+    pub fn local() -> Self {
+        Header {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Why we can't talk to a peer sending this header, if we can't
+    pub fn incompatibility(&self) -> Option<String> {
+        if self.version < MIN_PROTOCOL_VERSION {
+            Some(format!(
+                "peer speaks protocol v{}, but we need at least v{MIN_PROTOCOL_VERSION}",
+                self.version
+            ))
+        } else if self.min_version > PROTOCOL_VERSION {
+            Some(format!(
+                "peer needs protocol v{} or newer, but we speak v{PROTOCOL_VERSION}",
+                self.min_version
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Optional protocol features a peer supports
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// History catch-up with [`Payload::HistoryReq`] and [`Payload::HistoryWant`]
+    pub const HISTORY: Self = Self(1 << 0);
+    /// Leader election with [`Payload::Elect`] and [`Payload::Leader`]
+    pub const ELECTION: Self = Self(1 << 1);
+    /// Merging split rooms with [`Payload::Merge`]
+    pub const MERGE: Self = Self(1 << 2);
+    /// End-to-end encryption with [`ForwardPayload::Sealed`]
+    pub const E2E: Self = Self(1 << 3);
+    /// Joining protected rooms with [`Payload::Challenge`], [`Payload::Proof`] and
+    /// [`Payload::Admit`]
+    pub const AUTH: Self = Self(1 << 4);
+    /// Sharing the room's bans and mutes with [`Payload::Moderation`]
+    pub const MODERATION: Self = Self(1 << 5);
+    /// Renaming with [`Payload::Rename`], room topics with [`Payload::Topic`], and
+    /// [`ForwardPayload::Action`]
+    pub const CHAT: Self = Self(1 << 6);
+    /// Proving the id a peer claims with [`Payload::Identify`] and [`Payload::IdentityProof`]
+    pub const IDENTITY: Self = Self(1 << 7);
+    /// Handing out group keys with signed keys, in [`Payload::SignedKey`],
+    /// [`Payload::SignedDirectory`] and [`Payload::KeyHandout`]
+    pub const SIGNED_KEYS: Self = Self(1 << 8);
+
+    /// Everything this build supports
+    pub fn local() -> Self {
+        Self::HISTORY
+            | Self::ELECTION
+            | Self::MERGE
+            | Self::E2E
+            | Self::AUTH
+            | Self::MODERATION
+            | Self::CHAT
+            | Self::IDENTITY
+            | Self::SIGNED_KEYS
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
+    pub header: Header,
     pub payload: Payload,
 }
 
 impl Message {
     pub fn new(payload: Payload) -> Self {
-        Message { payload }
+        Message {
+            header: Header::local(),
+            payload,
+        }
     }
 }
 
+/// Variants are only ever appended, so that older peers can still decode the ones they know
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Payload {
-    Forward(Peer, ForwardPayload),
-    JoinReq(Peer),
+    Forward(Envelope),
+    JoinReq(Peer, Capabilities),
     Sync(Room),
+    /// Ask the receiver to lead the room for the given term, after the admin was lost
+    Elect(u64, Peer, Capabilities),
+    /// The given peer leads the room for the given term, reconnect to it
+    Leader(Mandate),
+    /// Sent by the losing admin of a split room, folding its side into the receiver's. Its
+    /// history follows once it's in, like any member's.
+    Merge(Room),
+    /// Ask the admin for the messages it holds besides the given ones, which the sender holds
+    HistoryReq(Vec<MessageId>),
+    /// Messages the receiver lacks, in reply to a [`Payload::HistoryReq`] or
+    /// [`Payload::HistoryWant`]
+    History(Vec<Envelope>),
+    /// The receiver can't join, for the given reason
+    Reject(String),
+    /// The sender is leaving the room for good, rather than having dropped out
+    Leave,
+    /// The room with the given id is protected, prove knowledge of its secret for this nonce
+    Challenge(u64, Nonce),
+    /// Answer to a [`Payload::Challenge`]
+    Proof([u8; 32]),
+    /// The proof was accepted, here's the room key sealed with the secret that was proven
+    Admit(Vec<u8>),
+    /// Bans and mutes of the room, for whoever leads it next
+    Moderation(Moderation),
+    /// The sender goes by the given name from now on
+    Rename(String),
+    /// Set the topic of the room, or tell members what it is
+    Topic(String),
+    /// Prove holding the key of the id just claimed, by signing this nonce along with the id of
+    /// the room and of its admin. The admin vouches for its certificate with the signature.
+    Identify(u64, PeerId, Nonce, Signature),
+    /// Answer to a [`Payload::Identify`]
+    IdentityProof(Signature),
+    /// The sender's key, which group keys should be wrapped for
+    SignedKey(SignedKey),
+    /// Keys of the room's members, the first of which is the keeper handing out group keys
+    SignedDirectory(Vec<(Peer, SignedKey)>),
+    /// Group key of an epoch, wrapped for each member by the keeper
+    KeyHandout(KeyHandout),
+    /// Messages the admin lacks of those a member holds, e.g. after a failover, which the
+    /// member sends in a [`Payload::History`]
+    HistoryWant(Vec<MessageId>),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Payload {
+    /// Capabilities a peer needs to understand this payload
+    pub fn requires(&self) -> Capabilities {
+        match self {
+            Payload::Elect(..) | Payload::Leader(..) => Capabilities::ELECTION,
+            Payload::Merge(..) => Capabilities::MERGE,
+            Payload::HistoryReq(..) | Payload::HistoryWant(..) => Capabilities::HISTORY,
+            Payload::History(entries) => entries
+                .iter()
+                .map(Envelope::requires)
+                .fold(Capabilities::HISTORY, |a, b| a | b),
+            Payload::Forward(envelope) => envelope.requires(),
+            Payload::Challenge(..) | Payload::Proof(..) | Payload::Admit(..) => Capabilities::AUTH,
+            Payload::Moderation(..) => Capabilities::MODERATION,
+            Payload::Rename(..) | Payload::Topic(..) => Capabilities::CHAT,
+            Payload::Identify(..) | Payload::IdentityProof(..) => Capabilities::IDENTITY,
+            Payload::SignedKey(..) | Payload::SignedDirectory(..) | Payload::KeyHandout(..) => {
+                Capabilities::SIGNED_KEYS
+            }
+            _ => Capabilities::default(),
+        }
+    }
+
+    /// Id of the peer asking to join or lead the room with this payload, if it's such a
+    /// request
+    pub(crate) fn claimant(&self) -> Option<&PeerId> {
+        match self {
+            Payload::JoinReq(peer, _) | Payload::Elect(_, peer, _) => Some(peer.id()),
+            Payload::Merge(room) => room.hierarchy.admin().map(Peer::id),
+            _ => None,
+        }
+    }
+
+    /// Capabilities the peer making this request says it has. Admins merging their side of
+    /// a room are assumed to be as recent as us.
+    pub(crate) fn claimed_capabilities(&self) -> Capabilities {
+        match self {
+            Payload::JoinReq(_, capabilities) | Payload::Elect(_, _, capabilities) => *capabilities,
+            _ => Capabilities::local(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum ForwardPayload {
     Text(String),
     Notification(String),
+    /// One of the above, encrypted for the room's members
+    Sealed(Sealed),
+    /// The sender describing what it does, as in "alice waves"
+    Action(String),
+}
+
+/// Word that `leader` leads the room `room_id` for `term`, signed by whoever gives it: the
+/// admin handing over, or a peer pointing others at the admin it knows
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mandate {
+    pub room_id: u64,
+    pub term: u64,
+    pub leader: Peer,
+    pub signer: PeerId,
+    signature: Signature,
+}
+
+impl Mandate {
+    /// Our word that `leader` leads the room `room_id` for `term`, signed with our identity
+    pub fn new(room_id: u64, term: u64, leader: Peer) -> Self {
+        Self::signed_by(Identity::local(), room_id, term, leader)
+    }
+
+    /// The word of the holder of `identity` that `leader` leads the room `room_id` for `term`
+    pub(crate) fn signed_by(identity: &Identity, room_id: u64, term: u64, leader: Peer) -> Self {
+        let signer = identity.id();
+        let signature = identity.sign(&mandate_bytes(room_id, term, &leader, &signer));
+        Mandate {
+            room_id,
+            term,
+            leader,
+            signer,
+            signature,
+        }
+    }
+
+    /// Whether the mandate was signed by its claimed signer, and hasn't been tampered with
+    pub fn verify(&self) -> bool {
+        let signed = mandate_bytes(self.room_id, self.term, &self.leader, &self.signer);
+        self.signer.verify(&signed, &self.signature)
+    }
+
+    /// Whether `signer` gave this word, for the room `room_id`
+    pub fn is_from(&self, signer: &PeerId, room_id: u64) -> bool {
+        self.signer == *signer && self.room_id == room_id && self.verify()
+    }
+}
+
+fn mandate_bytes(room_id: u64, term: u64, leader: &Peer, signer: &PeerId) -> Vec<u8> {
+    postcard::to_allocvec(&("vlawn mandate", room_id, term, leader, signer)).unwrap()
+}
+
+/// Unique identity of a message, chosen by its sender
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId(pub u128);
+
+impl MessageId {
+    pub fn random() -> Self {
+        MessageId(rand::random())
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:032x}", self.0)
+    }
+}
+
+/// A [`ForwardPayload`] along with who sent it, when, and where it sits in the history
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub id: MessageId,
+    pub sender: Peer,
+    /// Milliseconds since the unix epoch, by the sender's clock
+    pub sent_at: i64,
+    /// Position in the room's history, assigned by the admin when relaying
+    pub seq: Option<u64>,
+    pub payload: ForwardPayload,
+    /// Made by the sender over everything but `seq`
+    signature: Signature,
+}
+
+impl Envelope {
+    /// A message written by us, `sender` being our own peer, signed with our identity
+    pub fn new(sender: Peer, payload: ForwardPayload) -> Self {
+        Self::signed_by(Identity::local(), sender, payload)
+    }
+
+    /// A message written by the holder of `identity`, `sender` being its peer
+    pub(crate) fn signed_by(identity: &Identity, sender: Peer, payload: ForwardPayload) -> Self {
+        let id = MessageId::random();
+        let sent_at = chrono::Utc::now().timestamp_millis();
+        let signature = identity.sign(&signed_bytes(id, &sender, sent_at, &payload));
+        Envelope {
+            id,
+            sender,
+            sent_at,
+            seq: None,
+            payload,
+            signature,
+        }
+    }
+
+    /// Whether the message was signed by its claimed sender, and hasn't been tampered with
+    pub fn verify(&self) -> bool {
+        let signed = signed_bytes(self.id, &self.sender, self.sent_at, &self.payload);
+        self.sender.id().verify(&signed, &self.signature)
+    }
+
+    /// Capabilities a peer needs to decode this message
+    fn requires(&self) -> Capabilities {
+        match self.payload {
+            ForwardPayload::Sealed(_) => Capabilities::E2E,
+            ForwardPayload::Action(_) => Capabilities::CHAT,
+            _ => Capabilities::default(),
+        }
+    }
+
+    /// Ordering of messages in the history: by sequence number, then send time
+    pub(crate) fn order_key(&self) -> (Option<u64>, i64, MessageId) {
+        (self.seq, self.sent_at, self.id)
+    }
+}
+
+fn signed_bytes(id: MessageId, sender: &Peer, sent_at: i64, payload: &ForwardPayload) -> Vec<u8> {
+    postcard::to_allocvec(&(id, sender.id(), sent_at, payload)).unwrap()
 }
@@ -1,34 +1,57 @@
+mod beacon;
+mod error;
 mod event;
 mod handler;
+mod history;
 mod message;
+mod moderation;
 mod peer;
 mod state;
+mod switchboard;
 
+pub use beacon::*;
+pub use error::*;
 pub use event::*;
 pub use handler::*;
+pub use history::*;
 pub use message::*;
+pub use moderation::*;
 pub use peer::*;
 pub use state::*;
+pub use switchboard::*;
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Port peers' websocket servers listen on unless told otherwise
+pub const WS_PORT: u16 = 57185;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct Hierarchy(pub Vec<Peer>);
 
 impl Hierarchy {
-    pub fn new() -> Self {
-        Self(vec![Peer::get_local()])
-    }
-
     pub fn push(&mut self, peer: Peer) {
-        self.0.push(peer);
+        if !self.0.contains(&peer) {
+            self.0.push(peer);
+        }
     }
 
-    pub fn next_leader(&mut self) -> Option<&Peer> {
-        self.0.remove(0);
+    /// The peer leading the room
+    pub fn admin(&self) -> Option<&Peer> {
         self.0.first()
     }
 
+    /// Peers that should take over if the admin is lost, in order of precedence
+    pub fn successors(&self) -> impl Iterator<Item = &Peer> {
+        self.0.iter().skip(1)
+    }
+
+    /// Replace the entry of `peer` with this copy of it, e.g. after it was renamed
+    pub fn update(&mut self, peer: &Peer) {
+        if let Some(entry) = self.0.iter_mut().find(|p| *p == peer) {
+            *entry = peer.clone();
+        }
+    }
+
     pub fn remove(&mut self, peer: &Peer) {
         self.0.retain(|f| f != peer);
     }
@@ -36,15 +59,57 @@ impl Hierarchy {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Room {
+    /// Random identity of the room, which survives failover unlike the admin's address
+    id: u64,
     name: String,
     hierarchy: Hierarchy,
+    /// Election term, bumped every time leadership changes hands
+    term: u64,
 }
 
 impl Room {
-    pub fn new() -> Self {
+    /// A new room led by `admin`
+    pub fn new(admin: Peer) -> Self {
         Self {
+            id: rand::random(),
             name: crate::admin::room::random_room_name(),
-            hierarchy: Hierarchy::new(),
+            hierarchy: Hierarchy(vec![admin]),
+            term: 0,
         }
     }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn hierarchy(&self) -> &Hierarchy {
+        &self.hierarchy
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+}
+
+/// How a room we create should be run
+#[derive(Debug, Clone, Default)]
+pub struct RoomOptions {
+    /// Name the room goes by, instead of a random one
+    pub name: Option<String>,
+    /// Relay messages without holding the group key, so we can't read the room ourselves
+    pub blind: bool,
+    /// Secret joiners need to know, unless they have an invite code
+    pub passphrase: Option<String>,
+    /// Only let in peers with a passphrase or invite code, even without a passphrase set
+    pub invite_only: bool,
+}
+
+impl RoomOptions {
+    pub fn is_protected(&self) -> bool {
+        self.passphrase.is_some() || self.invite_only
+    }
 }
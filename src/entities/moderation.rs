@@ -0,0 +1,127 @@
+use std::{fmt, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Peer, PeerId};
+
+/// What the admin can do about a misbehaving member
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sanction {
+    /// Drop the member from the room, letting it rejoin
+    Kick,
+    /// Drop the member from the room, and keep it out
+    Ban,
+    Unban,
+    /// Stop relaying the member's messages
+    Mute,
+    Unmute,
+}
+
+impl Sanction {
+    /// Past tense, for notices such as "alice was banned"
+    pub fn past(self) -> &'static str {
+        match self {
+            Sanction::Kick => "kicked",
+            Sanction::Ban => "banned",
+            Sanction::Unban => "unbanned",
+            Sanction::Mute => "muted",
+            Sanction::Unmute => "unmuted",
+        }
+    }
+}
+
+impl fmt::Display for Sanction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Sanction::Kick => "kick",
+            Sanction::Ban => "ban",
+            Sanction::Unban => "unban",
+            Sanction::Mute => "mute",
+            Sanction::Unmute => "unmute",
+        })
+    }
+}
+
+/// A peer kept out of the room
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    pub peer: Peer,
+    /// Address the peer connected from as the admin saw it, which keeps it out even if it
+    /// comes back with a new identity
+    pub addr: Option<IpAddr>,
+}
+
+/// Bans and mutes of a room, which the admin shares with the members so they outlive it
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Moderation {
+    banned: Vec<Ban>,
+    muted: Vec<Peer>,
+}
+
+impl Moderation {
+    /// Whether `peer`, connecting from `addr`, is kept out of the room
+    pub fn is_banned(&self, peer: &Peer, addr: Option<&IpAddr>) -> bool {
+        self.banned
+            .iter()
+            .any(|ban| ban.peer == *peer || (ban.addr.is_some() && ban.addr.as_ref() == addr))
+    }
+
+    pub fn is_muted(&self, id: &PeerId) -> bool {
+        self.muted.iter().any(|peer| peer.id() == id)
+    }
+
+    pub fn banned(&self) -> impl Iterator<Item = &Ban> {
+        self.banned.iter()
+    }
+
+    /// Returns whether `peer` wasn't banned already
+    pub fn ban(&mut self, peer: Peer, addr: Option<IpAddr>) -> bool {
+        if self.banned.iter().any(|ban| ban.peer == peer) {
+            return false;
+        }
+        self.banned.push(Ban { peer, addr });
+        true
+    }
+
+    pub fn unban(&mut self, peer: &Peer) {
+        self.banned.retain(|ban| ban.peer != *peer);
+    }
+
+    /// Returns whether `peer` wasn't muted already
+    pub fn mute(&mut self, peer: Peer) -> bool {
+        if self.muted.contains(&peer) {
+            return false;
+        }
+        self.muted.push(peer);
+        true
+    }
+
+    /// Returns whether `peer` was muted
+    pub fn unmute(&mut self, peer: &Peer) -> bool {
+        let before = self.muted.len();
+        self.muted.retain(|p| p != peer);
+        self.muted.len() != before
+    }
+}
+
+/// The peer among `peers` that `target` names, either by username or by a prefix of its
+/// fingerprint
+pub fn resolve<'a>(
+    peers: impl Iterator<Item = &'a Peer>,
+    target: &str,
+) -> Result<&'a Peer, String> {
+    let target = target.trim();
+    let matches: Vec<&Peer> = peers
+        .filter(|peer| {
+            peer.username() == target
+                || (target.len() >= 4 && peer.id().to_string().starts_with(target))
+        })
+        .collect();
+    match matches.as_slice() {
+        [peer] => Ok(peer),
+        [] => Err(format!("No peer named {target}")),
+        _ => Err(format!(
+            "Several peers are named {target}, pick one by its fingerprint"
+        )),
+    }
+}
@@ -1,18 +1,200 @@
-use std::net::IpAddr;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    net::{IpAddr, SocketAddr, SocketAddrV6},
+    str::FromStr,
+};
 
+use ed25519_dalek::{Signature, VerifyingKey};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+use crate::identity::Identity;
+
+use super::WS_PORT;
+
+/// Public key a peer signs its messages with, which is what tells peers apart
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct PeerId(pub [u8; 32]);
+
+impl PeerId {
+    /// Whether `signature` over `message` was made by this peer
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        VerifyingKey::from_bytes(&self.0)
+            .is_ok_and(|key| key.verify_strict(message, signature).is_ok())
+    }
+
+    /// Short form of the fingerprint, for telling apart peers with the same name
+    pub fn short(&self) -> String {
+        self.0[..4].iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{b:02x}"))
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = io::Error;
+
+    /// Parse the fingerprint as it's displayed
+    fn from_str(s: &str) -> io::Result<Self> {
+        let mut id = [0; 32];
+        if s.len() != 2 * id.len() || !s.is_ascii() {
+            return Err(invalid("a fingerprint is 64 hex digits"));
+        }
+        for (byte, digits) in id.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| invalid("bad fingerprint"))?;
+            *byte = u8::from_str_radix(digits, 16).map_err(|_| invalid("bad fingerprint"))?;
+        }
+        Ok(PeerId(id))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({})", self.short())
+    }
+}
+
+/// Where a peer's websocket server takes connections for one of its rooms
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Endpoint {
+    pub addr: IpAddr,
+    pub port: u16,
+    /// Which of the rooms sharing the port it is, as one process can serve several on it
+    pub slot: u16,
+    /// Index of the interface a link-local IPv6 address is reached over, or 0 if unknown.
+    /// Only meaningful on this host, so it's never sent to peers.
+    #[serde(skip)]
+    pub scope: u32,
+}
+
+impl Endpoint {
+    /// The first of the rooms listening on `port` at `addr`
+    pub fn new(addr: IpAddr, port: u16) -> Self {
+        Endpoint {
+            addr,
+            port,
+            slot: 0,
+            scope: 0,
+        }
+    }
+
+    /// Look up `host`, written as `host[:port][/slot]` like endpoints are displayed, and
+    /// assuming the default port if it has none. A link-local IPv6 address may name the
+    /// interface it's on after a `%`, by name or index.
+    pub fn resolve(host: &str) -> io::Result<Self> {
+        let host = host.trim();
+        let (host, slot) = match host.rsplit_once('/') {
+            Some((host, slot)) => (host, slot.parse().map_err(|_| invalid("bad room slot"))?),
+            None => (host, 0),
+        };
+        let (host, scope) = split_scope(host)?;
+        let host = host.as_str();
+        if let Ok(addr) = host.parse::<SocketAddr>() {
+            return Ok(Endpoint {
+                slot,
+                scope,
+                ..Endpoint::new(addr.ip(), addr.port())
+            });
+        }
+        // An IPv6 address is all colons, so it only has a port when bracketed
+        let bare = host.strip_prefix('[').and_then(|h| h.strip_suffix(']'));
+        if let Ok(addr) = bare.unwrap_or(host).parse::<IpAddr>() {
+            return Ok(Endpoint {
+                slot,
+                scope,
+                ..Endpoint::new(addr, WS_PORT)
+            });
+        }
+        let (name, port) = match host.rsplit_once(':') {
+            Some((name, port)) => (name, port.parse().map_err(|_| invalid("bad port"))?),
+            None => (host, WS_PORT),
+        };
+        let addr = dns_lookup::lookup_host(name)?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))?;
+        Ok(Endpoint {
+            slot,
+            ..Endpoint::new(addr, port)
+        })
+    }
+
+    /// Where to connect to, scoped to its interface if the address is link-local
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.addr {
+            IpAddr::V6(addr) if crate::ip::is_link_local(&self.addr) => {
+                SocketAddr::V6(SocketAddrV6::new(addr, self.port, 0, self.scope))
+            }
+            addr => SocketAddr::new(addr, self.port),
+        }
+    }
+}
+
+/// `host` without the `%scope` of an IPv6 address, and the index of the interface it names
+fn split_scope(host: &str) -> io::Result<(String, u32)> {
+    let Some((addr, rest)) = host.split_once('%') else {
+        return Ok((host.to_string(), 0));
+    };
+    let (scope, rest) = rest.split_at(rest.find(']').unwrap_or(rest.len()));
+    let index = match scope.parse() {
+        Ok(index) => index,
+        Err(_) => crate::ip::candidates()?
+            .into_iter()
+            .find(|c| c.interface == scope)
+            .and_then(|c| c.index)
+            .ok_or_else(|| invalid("no such interface"))?,
+    };
+    Ok((format!("{addr}{rest}"), index))
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, reason)
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.socket_addr())?;
+        match self.slot {
+            0 => Ok(()),
+            slot => write!(f, "/{slot}"),
+        }
+    }
+}
+
+/// A peer along with where to reach it. Peers are told apart by their id alone.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Peer {
     username: String,
     addr: IpAddr,
+    port: u16,
+    slot: u16,
+    id: PeerId,
 }
 
 impl Peer {
     pub fn get_local() -> Self {
         Self {
             username: whoami::username(),
-            addr: IpAddr::V4(crate::ip::get_local_ipv4()),
+            addr: crate::ip::local_addr(None),
+            port: WS_PORT,
+            slot: 0,
+            id: Identity::local().id(),
+        }
+    }
+
+    /// Someone other than us, reached at `endpoint`
+    #[cfg(test)]
+    pub(crate) fn new(username: &str, endpoint: Endpoint, id: PeerId) -> Self {
+        Self {
+            username: username.to_string(),
+            addr: endpoint.addr,
+            port: endpoint.port,
+            slot: endpoint.slot,
+            id,
         }
     }
 
@@ -20,7 +202,118 @@ impl Peer {
         &self.addr
     }
 
+    pub fn set_addr(&mut self, addr: IpAddr) {
+        self.addr = addr;
+    }
+
+    /// Where to connect to the peer
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            addr: self.addr,
+            port: self.port,
+            slot: self.slot,
+            scope: 0,
+        }
+    }
+
+    /// Take connections on `port`, as the room in `slot` there
+    pub fn set_port(&mut self, port: u16, slot: u16) {
+        self.port = port;
+        self.slot = slot;
+    }
+
     pub fn username(&self) -> &String {
         &self.username
     }
+
+    pub fn set_username(&mut self, username: String) {
+        self.username = username;
+    }
+
+    pub fn id(&self) -> &PeerId {
+        &self.id
+    }
+}
+
+impl PartialEq for Peer {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Peer {}
+
+impl Hash for Peer {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(addr: &str, port: u16, slot: u16, scope: u32) -> Endpoint {
+        Endpoint {
+            addr: addr.parse().unwrap(),
+            port,
+            slot,
+            scope,
+        }
+    }
+
+    #[test]
+    fn resolves_addresses() {
+        let resolve = |host| Endpoint::resolve(host).unwrap();
+        assert_eq!(resolve("10.0.0.1"), endpoint("10.0.0.1", WS_PORT, 0, 0));
+        assert_eq!(resolve(" 10.0.0.1:4000 "), endpoint("10.0.0.1", 4000, 0, 0));
+        assert_eq!(resolve("10.0.0.1:4000/2"), endpoint("10.0.0.1", 4000, 2, 0));
+        assert_eq!(resolve("10.0.0.1/2"), endpoint("10.0.0.1", WS_PORT, 2, 0));
+        assert_eq!(resolve("fd00::1"), endpoint("fd00::1", WS_PORT, 0, 0));
+        assert_eq!(resolve("[fd00::1]"), endpoint("fd00::1", WS_PORT, 0, 0));
+        assert_eq!(resolve("[fd00::1]:4000/1"), endpoint("fd00::1", 4000, 1, 0));
+    }
+
+    #[test]
+    fn resolves_scopes() {
+        let resolve = |host| Endpoint::resolve(host).unwrap();
+        assert_eq!(resolve("fe80::1%3"), endpoint("fe80::1", WS_PORT, 0, 3));
+        assert_eq!(
+            resolve("[fe80::1%3]:4000/1"),
+            endpoint("fe80::1", 4000, 1, 3)
+        );
+    }
+
+    #[test]
+    fn resolves_names() {
+        let resolved = Endpoint::resolve("localhost:4000/1").unwrap();
+        assert!(resolved.addr.is_loopback());
+        assert_eq!((resolved.port, resolved.slot), (4000, 1));
+    }
+
+    #[test]
+    fn resolves_what_it_displays() {
+        for shown in [
+            endpoint("10.0.0.1", 4000, 0, 0),
+            endpoint("10.0.0.1", 4000, 2, 0),
+            endpoint("fe80::1", 4000, 1, 3),
+        ] {
+            assert_eq!(Endpoint::resolve(&shown.to_string()).unwrap(), shown);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_hosts() {
+        for host in [
+            "10.0.0.1/x",
+            "10.0.0.1:4000/70000",
+            "10.0.0.1:x",
+            "10.0.0.1:70000",
+            "[fd00::1]:x",
+            "fe80::1%no-such-interface",
+        ] {
+            let error = Endpoint::resolve(host).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{host}");
+        }
+    }
 }
@@ -1,163 +1,1423 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crossbeam_channel::Sender as ChSender;
+use crossbeam_channel::{Receiver, Sender as ChSender};
+use ed25519_dalek::Signature;
 use postcard::to_allocvec;
-use ws::{connect, Sender as WsSender};
+use ws::{connect, CloseCode, Sender as WsSender};
 
-use crate::entities::Hierarchy;
+use crate::{
+    access::{self, Key, Nonce, Protection},
+    admin::beacon::Announcer,
+    crypto::{Keyring, SignedKey},
+    entities::Hierarchy,
+    identity::{self, Identity},
+    ip,
+    member::discover::Listener,
+    relay,
+    tls::Tls,
+};
 
-use super::{Event, ForwardPayload, Handler, Message, Payload, Peer, Room};
+use super::{
+    moderation, next_connection_id, Beacon, Capabilities, Endpoint, Entry, Envelope, Event,
+    ForwardPayload, Handler, Heartbeat, History, Mandate, Message, MessageId, Moderation, Payload,
+    Peer, PeerId, Room, RoomOptions, Sanction, Switchboard, Update, VlawnError,
+};
+
+/// Discovered rooms that haven't announced themselves for this long are forgotten
+const BEACON_TTL: Duration = Duration::from_secs(5);
+/// How long to wait for our connections to close after leaving a room
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn send(sender: &WsSender, payload: Payload) -> Result<(), VlawnError> {
+    let msg_vec = to_allocvec(&Message::new(payload))?;
+    Ok(sender.send(msg_vec)?)
+}
+
+/// Send `payload` to the admin, noting in the history if that failed. The connection closing
+/// is reported on its own, and starts an election.
+fn tell_admin(history: &mut History, admin: &WsSender, payload: Payload) {
+    if let Err(e) = send(admin, payload) {
+        log::warn!("Failed to send to the admin: {e}");
+        history.error(format!("Could not reach the admin: {e}"));
+    }
+}
+
+/// The term a peer claims, bounded to the one after `current`, as an election never skips one
+fn bounded_term(current: u64, claimed: u64) -> u64 {
+    claimed.min(current.saturating_add(1))
+}
+
+/// Queue `event` for ourselves
+fn queue(events_tx: &ChSender<Event>, event: Event) {
+    // The receiving end is only dropped along with the state machine
+    if let Err(e) = events_tx.send(event) {
+        log::error!("Failed to queue {:?}", e.into_inner());
+    }
+}
+
+/// Check that `admin`, asking us on connection `con_id` to sign `nonce` for the room `room_id`,
+/// vouched with `signature` for the certificate presented there, and that it's the one pinned
+/// for it. Returns why we refuse the admin otherwise.
+fn check_certificate(
+    tls: Option<&Tls>,
+    con_id: u32,
+    room_id: u64,
+    admin: &PeerId,
+    nonce: &Nonce,
+    signature: &Signature,
+) -> Result<(), String> {
+    let fingerprint = match tls {
+        Some(tls) => tls
+            .presented(con_id)
+            .ok_or("No certificate was presented, refusing to connect")?,
+        None => String::new(),
+    };
+    if !identity::verify_certificate(admin, nonce, room_id, &fingerprint, signature) {
+        return Err(format!(
+            "{} didn't vouch for the connection, refusing to connect",
+            admin.short()
+        ));
+    }
+    match tls.map(|tls| tls.pin(admin, &fingerprint)) {
+        Some(Err(_)) => Err(format!(
+            "Certificate of {} changed to {fingerprint}, refusing to connect. \
+             If that's expected, remove {admin} from known_admins",
+            admin.short()
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Reconcile `history` with the admin's, telling it which messages we hold so each side gets
+/// what the other lacks
+fn request_history(history: &mut History, admin: &WsSender) {
+    let held = history.ids();
+    tell_admin(history, admin, Payload::HistoryReq(held));
+}
+
+/// Key to answer a challenge for the room `room_id` with: its room key if we were admitted
+/// before, or else the secret we were given
+fn credential(room_key: Option<(u64, Key)>, secret: Option<&str>, room_id: u64) -> Option<Key> {
+    match room_key {
+        Some((id, key)) if id == room_id => Some(key),
+        _ => secret.map(|s| access::derive(s, room_id)),
+    }
+}
+
+/// Send the room's key directory to everyone, and rekey the room if we're its keeper
+fn publish_directory(keys: &mut Keyring, state: &AdminState, me: &Peer) {
+    let directory = state.directory();
+    state.broadcast(Payload::SignedDirectory(directory.clone()));
+    if let Some(handout) = keys.rekey(me, &directory) {
+        keys.accept(me, &handout);
+        state.broadcast(Payload::KeyHandout(handout));
+    }
+}
+
+/// Record a notification from `sender` in the history and relay it to the room
+fn notify(history: &mut History, state: &AdminState, sender: &Peer, text: String) {
+    let mut envelope = Envelope::new(sender.clone(), ForwardPayload::Notification(text));
+    envelope.seq = Some(history.next_seq());
+    if history.insert(envelope.clone()) {
+        state.broadcast(Payload::Forward(envelope));
+    }
+}
 
 pub struct StateManager {
     state: State,
     peer: Peer,
     events_tx: ChSender<Event>,
-    history: Vec<(Peer, ForwardPayload)>,
+    history: History,
+    keys: Keyring,
+    /// Messages we submitted before holding a group key to seal them with
+    outbox: Vec<ForwardPayload>,
+    /// Incoming connections accepted while we aren't admin, e.g. from peers electing us
+    pending: HashMap<u32, WsSender>,
+    /// Addresses incoming connections came from, as seen by us rather than claimed
+    remote_addrs: HashMap<u32, IpAddr>,
+    /// Interface we last reached a peer over, which the link-local addresses peers share
+    /// without one are on as well
+    scope: u32,
+    heartbeat: Heartbeat,
+    /// Encrypts our links to other peers, if enabled
+    tls: Option<Arc<Tls>>,
+    /// Why the last room we tried to join turned us away
+    rejection: Option<String>,
+    /// What last went wrong outside of a room
+    error: Option<String>,
+    /// Passphrase or invite code for the room we're joining
+    secret: Option<String>,
+    /// Key we answered the last challenge with
+    proven: Option<Key>,
+    /// Key of the protected room we're in, which lets us back in after a failover
+    room_key: Option<(u64, Key)>,
+    /// Bans and mutes of the room we're in as last shared by its admin, enforced should we
+    /// take over
+    moderation: Moderation,
+    /// Topic of the room we're in as last shared by its admin
+    topic: Option<String>,
+    subscribers: Vec<ChSender<Update>>,
 }
 
 impl StateManager {
-    pub fn new(events_tx: ChSender<Event>) -> Self {
+    pub fn new(events_tx: ChSender<Event>, heartbeat: Heartbeat, tls: Option<Arc<Tls>>) -> Self {
         StateManager {
             state: State::Initial,
             peer: Peer::get_local(),
             events_tx,
-            history: Vec::new(),
+            history: History::default(),
+            keys: Keyring::new(),
+            outbox: Vec::new(),
+            pending: HashMap::new(),
+            remote_addrs: HashMap::new(),
+            scope: 0,
+            heartbeat,
+            tls,
+            rejection: None,
+            error: None,
+            secret: None,
+            proven: None,
+            room_key: None,
+            moderation: Moderation::default(),
+            topic: None,
+            subscribers: Vec::new(),
         }
     }
 
-    pub fn history(&self) -> &Vec<(Peer, ForwardPayload)> {
-        &self.history
+    pub fn tls(&self) -> Option<Arc<Tls>> {
+        self.tls.clone()
+    }
+
+    /// Who we are to other peers
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+
+    /// Whether messages we send are sealed, which takes a group key from the room's keeper
+    pub fn holds_group_key(&self) -> bool {
+        self.keys.has_key()
+    }
+
+    /// Messages of the room we're in, interleaved with what happened on our end
+    pub fn timeline(&self) -> Vec<Entry<'_>> {
+        self.history.timeline()
+    }
+
+    /// Contents of a message from the history, unless it's sealed with a key we weren't given
+    pub fn reveal(&self, envelope: &Envelope) -> Option<ForwardPayload> {
+        self.keys.open(&envelope.payload)
+    }
+
+    /// Why the last room we tried to join turned us away, cleared when read
+    pub fn take_rejection(&mut self) -> Option<String> {
+        self.rejection.take()
+    }
+
+    /// What last went wrong outside of a room, cleared when read
+    pub fn take_error(&mut self) -> Option<String> {
+        self.error.take()
     }
 
     pub fn peers(&self) -> Option<&Hierarchy> {
         match &self.state {
             State::Admin(state) => Some(&state.room.hierarchy),
             State::Member(state) => Some(&state.room.hierarchy),
+            State::Electing(state) => Some(&state.room.hierarchy),
+            _ => None,
+        }
+    }
+
+    /// Bans and mutes of the room we're in
+    pub fn moderation(&self) -> Option<&Moderation> {
+        match &self.state {
+            State::Admin(state) => Some(&state.moderation),
+            State::Member(_) | State::Electing(_) => Some(&self.moderation),
+            _ => None,
+        }
+    }
+
+    pub fn topic(&self) -> Option<&String> {
+        match &self.state {
+            State::Admin(state) => state.topic.as_ref(),
+            State::Member(_) | State::Electing(_) => self.topic.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Whether we're in a room with an admin, so what we submit gets posted
+    pub fn in_room(&self) -> bool {
+        matches!(self.state, State::Admin(_) | State::Member(_))
+    }
+
+    /// Whether we left the room and our connections are closed, or gave up waiting on them
+    pub fn has_left(&self) -> bool {
+        match &self.state {
+            State::Leaving(state) => state.waiting.is_empty() || Instant::now() >= state.deadline,
+            _ => false,
+        }
+    }
+
+    /// Whether the room we're joining challenged us, and we have no secret to answer with
+    pub fn awaiting_secret(&self) -> bool {
+        matches!(
+            &self.state,
+            State::Connect(ConnectState {
+                challenge: Some(_),
+                answered: false,
+                ..
+            })
+        )
+    }
+
+    /// Outstanding invite codes, if we lead a protected room
+    pub fn invites(&self) -> Option<Vec<&String>> {
+        match &self.state {
+            State::Admin(state) => state.protection.as_ref().map(|p| p.invites().collect()),
+            _ => None,
+        }
+    }
+
+    /// Rooms seen on the LAN, if currently discovering
+    pub fn discovered(&self) -> Option<&Vec<DiscoveredRoom>> {
+        match &self.state {
+            State::Discover(state) => Some(&state.rooms),
             _ => None,
         }
     }
 
+    /// Where to join the discovered room called `name`, if it was seen
+    pub fn discovered_room(&self, name: &str) -> Option<Endpoint> {
+        self.discovered()?
+            .iter()
+            .find(|room| room.beacon.name == name)
+            .map(DiscoveredRoom::endpoint)
+    }
+
+    /// Accept connections from other peers on `port`, or on one the system picks if it's 0,
+    /// at `bind_addr` or else at every address.
+    ///
+    /// Reports [`Event::Error`] if we can't, as nobody can join or elect us then.
+    pub fn listen(&mut self, bind_addr: Option<IpAddr>, port: u16) {
+        match Switchboard::listen(bind_addr, port, self.heartbeat, self.tls.clone()) {
+            Ok(switchboard) => self.share(&switchboard),
+            Err(e) => {
+                log::error!("Failed to listen on port {port}: {e}");
+                queue(
+                    &self.events_tx,
+                    Event::Error(format!(
+                        "Can't accept connections on port {port}, others won't be able to join: {e}"
+                    )),
+                );
+            }
+        }
+    }
+
+    /// Accept connections through `switchboard`, sharing its port with the other rooms on it
+    pub fn share(&mut self, switchboard: &Switchboard) {
+        let slot = switchboard.attach(self.events_tx.clone());
+        self.peer.set_port(switchboard.port(), slot);
+    }
+
+    /// Connect to the peer at `endpoint` and greet it with `payload`.
+    ///
+    /// Reports [`Event::Connected`] once open, or [`Event::ConnectFailed`].
+    fn dial(&mut self, endpoint: Endpoint, payload: Payload) {
+        let endpoint = self.scoped(endpoint);
+        let msg_vec = match to_allocvec(&Message::new(payload)) {
+            Ok(msg_vec) => msg_vec,
+            Err(e) => {
+                log::error!("Failed to encode greeting for {endpoint}: {e}");
+                queue(&self.events_tx, Event::ConnectFailed(endpoint));
+                return;
+            }
+        };
+        let events_tx = self.events_tx.clone();
+        let heartbeat = self.heartbeat;
+        let tls = self.tls.clone();
+        let scheme = if tls.is_some() { "wss" } else { "ws" };
+
+        let spawned = std::thread::Builder::new()
+            .name("connect".into())
+            .spawn(move || {
+                let opened = Arc::new(AtomicBool::new(false));
+                let target = match endpoint.socket_addr() {
+                    target @ SocketAddr::V4(_) => target,
+                    target @ SocketAddr::V6(_) => match relay::spawn(target) {
+                        Ok(relay) => relay,
+                        Err(e) => {
+                            log::warn!("Failed to relay connection to {target}: {e}");
+                            queue(&events_tx, Event::ConnectFailed(endpoint));
+                            return;
+                        }
+                    },
+                };
+                let url = format!("{scheme}://{target}/{}", endpoint.slot);
+                let result = connect(url, |out| {
+                    Handler::outgoing(
+                        events_tx.clone(),
+                        next_connection_id(),
+                        out,
+                        heartbeat,
+                        endpoint,
+                        msg_vec.clone(),
+                        opened.clone(),
+                    )
+                    .with_tls(tls.clone())
+                });
+                if let Err(e) = result {
+                    log::warn!("Connection to {endpoint} failed: {e}");
+                }
+                if !opened.load(Ordering::Relaxed) {
+                    queue(&events_tx, Event::ConnectFailed(endpoint));
+                }
+            });
+        if let Err(e) = spawned {
+            log::error!("Failed to start connecting to {endpoint}: {e}");
+            queue(&self.events_tx, Event::ConnectFailed(endpoint));
+        }
+    }
+
+    /// `endpoint` on the interface we last reached a peer over, unless it names its own
+    fn scoped(&mut self, mut endpoint: Endpoint) -> Endpoint {
+        match endpoint.scope {
+            0 => endpoint.scope = self.scope,
+            scope => self.scope = scope,
+        }
+        endpoint
+    }
+
+    fn join(&mut self, endpoint: Endpoint) {
+        let endpoint = self.scoped(endpoint);
+        // Tell the admin the address it reaches us at, which the others fail over to
+        self.peer
+            .set_addr(ip::local_addr(Some(endpoint.socket_addr())));
+        self.dial(
+            endpoint,
+            Payload::JoinReq(self.peer.clone(), Capabilities::local()),
+        );
+    }
+
+    /// Look for a new admin of `room` for `term`, asking `candidates` in order
+    fn elect(&mut self, room: Room, term: u64, candidates: VecDeque<Peer>, voters: Vec<Voter>) {
+        self.state = State::Electing(ElectingState {
+            room,
+            term,
+            candidates,
+            target: None,
+            link: None,
+            verified: false,
+            voters,
+            merging: None,
+        });
+        self.next_candidate();
+    }
+
+    /// Ask the next candidate to lead, or lead ourselves if we're next in line
+    fn next_candidate(&mut self) {
+        let State::Electing(state) = &mut self.state else {
+            return;
+        };
+        if let Some((_, link)) = state.link.take() {
+            let _ = link.close(CloseCode::Normal);
+        }
+        state.verified = false;
+
+        match state.candidates.pop_front() {
+            Some(candidate) if candidate != self.peer => {
+                log::info!(
+                    "Asking {} @ {} to lead term {}",
+                    candidate.username(),
+                    candidate.endpoint(),
+                    state.term
+                );
+                let endpoint = candidate.endpoint();
+                let payload = Payload::Elect(state.term, self.peer.clone(), Capabilities::local());
+                state.target = Some(candidate);
+                self.dial(endpoint, payload);
+            }
+            // The winner of a split didn't take us in, so we go on leading our side
+            None if state.merging.is_some() => {
+                log::warn!("Could not merge into the other side of the split room");
+                if let Some(merging) = state.merging.take() {
+                    self.state = State::Admin(merging);
+                }
+            }
+            // Everyone ahead of us is unreachable
+            _ => {
+                let room = state.room.clone();
+                let term = state.term;
+                let voters = std::mem::take(&mut state.voters);
+                self.promote(room, term, voters);
+            }
+        }
+    }
+
+    fn start_room(&mut self, options: RoomOptions) {
+        let mut room = Room::new(self.peer.clone());
+        if let Some(name) = &options.name {
+            room.name = name.clone();
+        }
+        let mut state = AdminState::from_room(room, self.events_tx.clone());
+        if !options.blind {
+            state.own_key = Some(self.keys.public());
+        }
+        if options.is_protected() {
+            let protection = Protection::new(state.room.id, options.passphrase.as_deref());
+            self.room_key = Some((state.room.id, protection.room_key()));
+            state.protection = Some(protection);
+        }
+        self.history.attach(&state.room);
+        self.history.note(format!("Created {}", state.room.name()));
+        if state.announcer.is_none() {
+            let text = "Could not announce the room, peers on the LAN won't find it";
+            self.history.error(text.to_string());
+        }
+        self.keys.attach(state.room.id);
+        publish_directory(&mut self.keys, &state, &self.peer);
+        self.state = State::Admin(Box::new(state));
+    }
+
+    /// Take over `room` for `term`, accepting the peers that elected us
+    fn promote(&mut self, mut room: Room, term: u64, voters: Vec<Voter>) {
+        log::info!("Promoting self to admin for term {term}");
+        room.hierarchy = Hierarchy(vec![self.peer.clone()]);
+        room.term = term;
+
+        self.history.attach(&room);
+        self.keys.attach(room.id);
+        let protection = self
+            .room_key
+            .filter(|(id, _)| *id == room.id)
+            .map(|(_, key)| Protection::from_room_key(key));
+        let mut state = AdminState::from_room(room, self.events_tx.clone());
+        state.own_key = Some(self.keys.public());
+        state.protection = protection;
+        state.moderation = std::mem::take(&mut self.moderation);
+        state.topic = self.topic.take();
+        state.clients = std::mem::take(&mut self.pending);
+        // Voters join like anyone else, proving who they are and their access first
+        for (con_id, peer, capabilities) in voters {
+            let request = Message::new(Payload::Elect(term, peer, capabilities));
+            queue(&self.events_tx, Event::Message(request, con_id));
+        }
+        let text = format!("{} now leads the room", self.peer.username());
+        notify(&mut self.history, &state, &self.peer, text);
+        if state.announcer.is_none() {
+            let text = "Could not announce the room, peers on the LAN won't find it";
+            self.history.error(text.to_string());
+        }
+        publish_directory(&mut self.keys, &state, &self.peer);
+        self.state = State::Admin(Box::new(state));
+    }
+
+    /// Hand our clients over to the admin of a newer term and follow it ourselves
+    fn step_down(&mut self, term: u64, leader: Peer) {
+        let State::Admin(state) = &mut self.state else {
+            return;
+        };
+        log::info!(
+            "Stepping down in favour of {} for term {term}",
+            leader.username()
+        );
+
+        let room_id = state.room.id;
+        state.broadcast(Payload::Leader(Mandate::new(room_id, term, leader.clone())));
+
+        let room = state.room.clone();
+        self.elect(room, term, VecDeque::from([leader]), Vec::new());
+    }
+
+    /// Yield to `winner`, the admin of the other side of a split room, by merging our
+    /// hierarchy and history into its room and moving our clients over
+    fn merge_into(&mut self, winner: Peer, term: u64) {
+        let state = match std::mem::replace(&mut self.state, State::Initial) {
+            State::Admin(state) => state,
+            other => {
+                self.state = other;
+                return;
+            }
+        };
+        log::info!(
+            "Room is split, merging into {} @ {} for term {term}",
+            winner.username(),
+            winner.endpoint()
+        );
+
+        // Our side only moves over once the winner proved who it is and took us in
+        let endpoint = winner.endpoint();
+        let payload = Payload::Merge(state.room.clone());
+        self.state = State::Electing(ElectingState {
+            room: state.room.clone(),
+            term,
+            candidates: VecDeque::new(),
+            target: Some(winner),
+            link: None,
+            verified: false,
+            voters: Vec::new(),
+            merging: Some(state),
+        });
+        self.dial(endpoint, payload);
+    }
+
+    /// Give up on the room we're connecting to, telling the user why
+    fn turn_away(&mut self, rejection: &str) {
+        log::error!("{rejection}");
+        if let State::Connect(state) = &self.state {
+            let _ = state.admin.close(CloseCode::Normal);
+        }
+        self.rejection = Some(rejection.to_string());
+        self.state = State::Initial;
+        queue(&self.events_tx, Event::Discover);
+    }
+
+    /// Say goodbye to the room we're in, handing it over first if we lead it
+    fn leave(&mut self, after: AfterLeave) {
+        if !self.outbox.is_empty() {
+            log::warn!(
+                "Dropping {} messages that were never sent",
+                self.outbox.len()
+            );
+            self.outbox.clear();
+        }
+        let mut waiting = match &mut self.state {
+            State::Admin(state) => {
+                let successor = state.room.hierarchy.successors().next().cloned();
+                let text = match &successor {
+                    Some(successor) => format!(
+                        "{} left, {} now leads the room",
+                        self.peer.username(),
+                        successor.username()
+                    ),
+                    None => format!("{} left", self.peer.username()),
+                };
+                notify(&mut self.history, state, &self.peer, text);
+
+                // Hand the room over explicitly, so members don't have to detect our loss
+                if let Some(successor) = successor {
+                    log::info!("Handing room over to {}", successor.username());
+                    let term = state.room.term.saturating_add(1);
+                    let mandate = Mandate::new(state.room.id, term, successor);
+                    state.broadcast(Payload::Leader(mandate));
+                }
+                for client in state.clients.values() {
+                    let _ = client.close(CloseCode::Away);
+                }
+                state.clients.keys().copied().collect()
+            }
+            State::Member(state) => {
+                tell_admin(&mut self.history, &state.admin, Payload::Leave);
+                let _ = state.admin.close(CloseCode::Away);
+                HashSet::from([state.admin_id])
+            }
+            State::Connect(state) => {
+                let _ = state.admin.close(CloseCode::Away);
+                HashSet::from([state.admin_id])
+            }
+            State::Electing(state) => {
+                let mut waiting = HashSet::new();
+                if let Some((link_id, link)) = state.link.take() {
+                    let _ = link.close(CloseCode::Away);
+                    waiting.insert(link_id);
+                }
+                // The side of a split room we were merging is still ours
+                for (con_id, client) in state.merging.take().into_iter().flat_map(|s| s.clients) {
+                    let _ = client.close(CloseCode::Away);
+                    waiting.insert(con_id);
+                }
+                waiting
+            }
+            _ => HashSet::new(),
+        };
+        // Peers that connected to us without joining yet, e.g. to elect us
+        for (con_id, sender) in self.pending.drain() {
+            let _ = sender.close(CloseCode::Away);
+            waiting.insert(con_id);
+        }
+
+        match after {
+            AfterLeave::Quit => self.state = State::Leaving(LeavingState::new(waiting)),
+            AfterLeave::Lobby => {
+                self.state = State::Initial;
+                queue(&self.events_tx, Event::Discover);
+            }
+            AfterLeave::Join(endpoint) => {
+                self.state = State::Initial;
+                self.join(endpoint);
+            }
+        }
+    }
+
+    /// Keep `payload` until we hold a group key to seal it with, rather than sending it for
+    /// the admin and anyone listening in to read
+    fn hold(&mut self, payload: ForwardPayload) {
+        if self.outbox.is_empty() {
+            let text = "Waiting for the room's key, your messages go out once it arrives";
+            self.history.note(text.to_string());
+        }
+        self.outbox.push(payload);
+    }
+
+    /// Go by `username` from now on, telling the room if we're in one
+    fn rename(&mut self, username: String) {
+        let text = format!("{} is now known as {username}", self.peer.username());
+        self.peer.set_username(username);
+        match &mut self.state {
+            State::Admin(state) => {
+                state.room.hierarchy.update(&self.peer);
+                state.announce();
+                state.sync_all();
+                notify(&mut self.history, state, &self.peer, text);
+            }
+            State::Member(state) => {
+                let payload = Payload::Rename(self.peer.username().clone());
+                tell_admin(&mut self.history, &state.admin, payload);
+            }
+            _ => {}
+        }
+    }
+
+    /// Subscribe to the lines added to the timeline of whichever room we're in
+    pub fn subscribe(&mut self) -> Receiver<Update> {
+        let (updates_tx, updates_rx) = crossbeam_channel::unbounded();
+        self.subscribers.push(updates_tx);
+        updates_rx
+    }
+
+    /// Act on `event`, then tell subscribers what it added to the timeline
     pub fn handle(&mut self, event: Event) {
+        self.transition(event);
+
+        if self.in_room() && self.keys.has_key() {
+            for payload in self.outbox.drain(..) {
+                queue(&self.events_tx, Event::SubmitMessage(payload));
+            }
+        }
+
+        let fresh = self.history.take_fresh();
+        if self.subscribers.is_empty() {
+            return;
+        }
+        for update in fresh {
+            let update = match update {
+                Update::Message(envelope, _) => {
+                    let payload = self.keys.open(&envelope.payload);
+                    Update::Message(envelope, payload)
+                }
+                notice => notice,
+            };
+            self.subscribers
+                .retain(|subscriber| subscriber.send(update.clone()).is_ok());
+        }
+    }
+
+    fn transition(&mut self, event: Event) {
+        match &event {
+            Event::Closed(con_id) => {
+                self.pending.remove(con_id);
+                self.remote_addrs.remove(con_id);
+            }
+            Event::Open(_, con_id, Some(addr)) => {
+                self.remote_addrs.insert(*con_id, *addr);
+            }
+            _ => {}
+        }
+
         match (&mut self.state, event) {
-            (State::Initial, Event::StartRoom) => self.state = State::Admin(AdminState::new()),
-            (State::Initial, Event::JoinSend(addr)) => {
-                let msg = Message::new(Payload::JoinReq(self.peer.clone()));
-                let msg_vec = to_allocvec(&msg).unwrap();
-                let events_tx = self.events_tx.clone();
-
-                std::thread::Builder::new()
-                    .name("connect".into())
-                    .spawn(move || {
-                        connect(format!("ws://{addr}:57185"), |out| {
-                            events_tx.send(Event::Open(out.clone())).unwrap();
-                            out.send(msg_vec.clone()).unwrap();
-
-                            Handler::new(events_tx.clone(), out.connection_id())
-                        })
-                        .unwrap();
+            (State::Initial, Event::StartRoom(options)) => self.start_room(options),
+            (State::Initial, Event::JoinSend(endpoint)) => self.join(endpoint),
+            (State::Initial, Event::Discover) => match Listener::spawn(self.events_tx.clone()) {
+                Ok(listener) => {
+                    self.state = State::Discover(DiscoverState {
+                        rooms: Vec::new(),
+                        _listener: listener,
                     })
-                    .unwrap();
+                }
+                Err(e) => {
+                    log::error!("Failed to start room discovery: {e}");
+                    self.error = Some(format!("Could not look for rooms on the LAN: {e}"));
+                }
+            },
+            (State::Discover(state), Event::Beacon(beacon, addr)) if beacon.verify() => {
+                let now = Instant::now();
+                state
+                    .rooms
+                    .retain(|r| now.duration_since(r.last_seen) < BEACON_TTL);
+                let scope = match addr {
+                    SocketAddr::V6(addr) => addr.scope_id(),
+                    SocketAddr::V4(_) => 0,
+                };
+                match state
+                    .rooms
+                    .iter_mut()
+                    // Admins announcing over both IPv4 and IPv6 are listed once
+                    .find(|r| r.beacon.id == beacon.id && r.beacon.admin == beacon.admin)
+                {
+                    Some(room) => {
+                        room.beacon = beacon;
+                        room.last_seen = now;
+                        // A link-local address only reaches the admin over one interface
+                        if ip::is_link_local(&room.addr) && !ip::is_link_local(&addr.ip()) {
+                            room.addr = addr.ip();
+                            room.scope = scope;
+                        }
+                    }
+                    None => state.rooms.push(DiscoveredRoom {
+                        beacon,
+                        addr: addr.ip(),
+                        scope,
+                        last_seen: now,
+                        latency: None,
+                    }),
+                }
+            }
+            (State::Discover(state), Event::Latency(addr, latency)) => state
+                .rooms
+                .iter_mut()
+                .filter(|r| r.addr == addr)
+                .for_each(|r| r.latency = Some(latency)),
+            (State::Discover(_), Event::JoinSend(endpoint)) => {
+                self.state = State::Initial;
+                self.join(endpoint);
+            }
+            (State::Discover(_), Event::StartRoom(options)) => self.start_room(options),
+            (State::Initial, Event::Connected(sender, con_id, _endpoint)) => {
+                self.proven = None;
+                self.state = State::Connect(ConnectState {
+                    admin: sender,
+                    admin_id: con_id,
+                    challenge: None,
+                    answered: false,
+                    verified: None,
+                });
+            }
+            (State::Initial, Event::ConnectFailed(endpoint)) => {
+                log::error!("Could not reach room at {endpoint}");
+                self.error = Some(format!("Could not reach a room at {endpoint}"));
+                queue(&self.events_tx, Event::Discover);
+            }
+            (State::Connect(state), Event::Closed(con_id)) if state.admin_id == con_id => {
+                log::error!("Room closed the connection before we joined");
+                self.error = Some("The room closed the connection before we joined".into());
+                self.state = State::Initial;
+                queue(&self.events_tx, Event::Discover);
             }
-            (State::Initial, Event::Open(sender)) => {
-                self.state = State::Connect(ConnectState { admin: sender });
+            // Only the room we dialed has anything to tell us before we're in it
+            (State::Connect(state), Event::Message(msg, con_id)) if state.admin_id == con_id => {
+                match msg.payload {
+                    Payload::Sync(room) => {
+                        let admin = room.hierarchy.admin().map(|admin| (room.id, *admin.id()));
+                        if state.verified.is_none() || state.verified != admin {
+                            self.turn_away(
+                                "The room isn't the one that vouched for the connection",
+                            );
+                            return;
+                        }
+                        let admin = state.admin.clone();
+                        self.moderation = Moderation::default();
+                        self.topic = None;
+                        self.history.attach(&room);
+                        self.history.note(format!("Joined {}", room.name()));
+                        self.keys.attach(room.id);
+                        request_history(&mut self.history, &admin);
+                        let key = Payload::SignedKey(self.keys.public());
+                        tell_admin(&mut self.history, &admin, key);
+                        self.state = State::Member(MemberState {
+                            room,
+                            admin,
+                            admin_id: state.admin_id,
+                        });
+                        log::info!("Joined room!")
+                    }
+                    Payload::Leader(mandate) if mandate.verify() => {
+                        log::info!("Redirected to admin @ {}", mandate.leader.endpoint());
+                        let _ = state.admin.close(CloseCode::Normal);
+                        self.state = State::Initial;
+                        self.join(mandate.leader.endpoint());
+                    }
+                    Payload::Reject(reason) => {
+                        log::error!("Room rejected us: {reason}");
+                        let _ = state.admin.close(CloseCode::Normal);
+                        self.rejection = Some(reason);
+                        self.secret = None;
+                        self.state = State::Initial;
+                        queue(&self.events_tx, Event::Discover);
+                    }
+                    Payload::Challenge(room_id, nonce)
+                        if state.verified.is_some_and(|(id, _)| id == room_id) =>
+                    {
+                        state.challenge = Some((room_id, nonce));
+                        match credential(self.room_key, self.secret.as_deref(), room_id) {
+                            Some(key) => {
+                                self.proven = Some(key);
+                                state.answered = true;
+                                let proof = Payload::Proof(access::prove(&key, &nonce));
+                                if let Err(e) = send(&state.admin, proof) {
+                                    log::warn!("Failed to answer challenge: {e}");
+                                }
+                            }
+                            None => log::info!("Room is protected, waiting for a passphrase"),
+                        }
+                    }
+                    Payload::Challenge(..) => {
+                        self.turn_away(
+                            "The room asked for a passphrase before proving who leads it",
+                        );
+                    }
+                    Payload::Identify(room_id, admin, nonce, signature) => {
+                        let tls = self.tls.as_deref();
+                        let checked =
+                            check_certificate(tls, con_id, room_id, &admin, &nonce, &signature);
+                        if let Err(rejection) = checked {
+                            self.turn_away(&rejection);
+                            return;
+                        }
+                        let proof = Identity::local().prove(&nonce, room_id, &admin);
+                        state.verified = Some((room_id, admin));
+                        if let Err(e) = send(&state.admin, Payload::IdentityProof(proof)) {
+                            log::warn!("Failed to prove our identity: {e}");
+                        }
+                    }
+                    Payload::Admit(sealed) => {
+                        if let (Some((room_id, nonce)), Some(key)) = (state.challenge, self.proven)
+                        {
+                            match access::open_room_key(&sealed, &key, &nonce) {
+                                Some(room_key) => self.room_key = Some((room_id, room_key)),
+                                None => {
+                                    log::warn!("Could not open the room key we were admitted with")
+                                }
+                            }
+                        }
+                    }
+                    payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
+                }
             }
-            (State::Connect(state), Event::Message(msg, _con_id)) => match msg.payload {
-                Payload::Sync(room) => {
-                    self.state = State::Member(MemberState {
-                        room,
-                        admin: state.admin.clone(),
-                    });
-                    log::info!("Joined room!")
+            (State::Connect(state), Event::Secret(secret)) => {
+                if let Some((room_id, nonce)) = state.challenge.filter(|_| !state.answered) {
+                    let key = access::derive(&secret, room_id);
+                    self.proven = Some(key);
+                    state.answered = true;
+                    let proof = Payload::Proof(access::prove(&key, &nonce));
+                    if let Err(e) = send(&state.admin, proof) {
+                        log::warn!("Failed to answer challenge: {e}");
+                    }
                 }
-                payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
-            },
+                self.secret = Some(secret);
+            }
             (State::Admin(state), Event::Message(msg, con_id)) => match &msg.payload {
-                Payload::JoinReq(peer) => {
-                    state.room.hierarchy.push(peer.clone());
-                    let msg = Message::new(Payload::Sync(state.room.clone()));
-                    let msg_vec = to_allocvec(&msg).unwrap();
-                    state
-                        .clients
-                        .iter()
-                        .find(|t| t.connection_id() == con_id)
-                        .unwrap()
-                        .send(msg_vec)
-                        .unwrap();
-                    state.peers.insert(con_id, peer.clone());
-                }
-                Payload::Forward(peer, payload) => {
-                    self.history.push((peer.clone(), payload.clone()));
-                    let msg_vec = to_allocvec(&(msg.clone())).unwrap();
-                    state.clients.iter().for_each(|c| {
-                        c.send(msg_vec.clone()).unwrap();
-                    });
+                Payload::JoinReq(peer, _) | Payload::Elect(_, peer, _)
+                    if state
+                        .moderation
+                        .is_banned(peer, self.remote_addrs.get(&con_id)) =>
+                {
+                    state.reject(con_id, "You are banned from this room");
+                }
+                // Who we are comes first, so joiners say nothing secret to whoever poses as us
+                Payload::JoinReq(..) | Payload::Elect(..) | Payload::Merge(..)
+                    if !state.identifies(con_id, &msg.payload) =>
+                {
+                    let fingerprint = self.tls.as_deref().map_or("", Tls::fingerprint);
+                    state.identify(con_id, msg.payload.clone(), fingerprint);
+                }
+                Payload::IdentityProof(proof) => {
+                    state.check_identity(con_id, proof, &self.events_tx)
+                }
+                Payload::JoinReq(..) | Payload::Elect(..) | Payload::Merge(..)
+                    if !state.admits(con_id) =>
+                {
+                    state.challenge(con_id, msg.payload.clone());
+                }
+                Payload::Proof(proof) => state.check_proof(con_id, proof, &self.events_tx),
+                payload if !state.admits(con_id) => {
+                    log::warn!("Ignoring {payload:?} from unadmitted connection {con_id}")
+                }
+                Payload::JoinReq(peer, capabilities) => {
+                    state.accept(con_id, peer.clone(), *capabilities);
+                    let text = format!("{} joined", peer.username());
+                    notify(&mut self.history, state, &self.peer, text);
+                }
+                Payload::Elect(term, peer, capabilities) => {
+                    state.room.term = state.room.term.max(bounded_term(state.room.term, *term));
+                    state.accept(con_id, peer.clone(), *capabilities);
+                }
+                Payload::Forward(envelope)
+                    if state.peers.get(&con_id) != Some(&envelope.sender) || !envelope.verify() =>
+                {
+                    log::warn!(
+                        "Dropping message {} from connection {con_id}, which isn't signed by it",
+                        envelope.id
+                    );
+                }
+                Payload::Forward(envelope) if state.moderation.is_muted(envelope.sender.id()) => {
+                    log::info!(
+                        "Not relaying message {} from muted {}",
+                        envelope.id,
+                        envelope.sender.username()
+                    );
+                }
+                Payload::Forward(envelope) => {
+                    let mut envelope = envelope.clone();
+                    envelope.seq = Some(self.history.next_seq());
+                    if self.history.insert(envelope.clone()) {
+                        state.broadcast(Payload::Forward(envelope));
+                    }
+                }
+                Payload::Merge(room) if room.id == state.room.id => {
+                    log::info!("Merging split room from {:?}", room.hierarchy.admin());
+                    state.room.term = state
+                        .room
+                        .term
+                        .max(bounded_term(state.room.term, room.term));
+
+                    // Its members join us like anyone else once told we lead, and its history
+                    // is reconciled with ours like theirs
+                    if let Some(admin) = room.hierarchy.admin() {
+                        state.accept(con_id, admin.clone(), Capabilities::local());
+                        let text = format!(
+                            "Merged with the side of the split room {} led",
+                            admin.username()
+                        );
+                        notify(&mut self.history, state, &self.peer, text);
+                    }
+                }
+                // Only a peer of the room may claim it won the next term, by its own word and on
+                // a connection of its own, never through one of our members
+                Payload::Leader(mandate)
+                    if !state.peers.contains_key(&con_id)
+                        && mandate.is_from(mandate.leader.id(), state.room.id)
+                        && state.room.hierarchy.0.contains(&mandate.leader)
+                        && mandate.term == state.room.term.saturating_add(1) =>
+                {
+                    self.step_down(mandate.term, mandate.leader.clone());
+                }
+                Payload::HistoryReq(held) if state.peers.contains_key(&con_id) => {
+                    let held: HashSet<MessageId> = held.iter().copied().collect();
+                    state.send_to(con_id, Payload::History(self.history.besides(&held)));
+                    let lacking = self.history.lacking(&held);
+                    if !lacking.is_empty() {
+                        state
+                            .wanted
+                            .insert(con_id, lacking.iter().copied().collect());
+                        state.send_to(con_id, Payload::HistoryWant(lacking));
+                    }
+                }
+                Payload::History(entries) => {
+                    // Messages the member kept while we didn't, e.g. from before a failover,
+                    // numbered from where we are like a merged room's
+                    let wanted = state.wanted.remove(&con_id).unwrap_or_default();
+                    for envelope in entries.iter().filter(|e| wanted.contains(&e.id)) {
+                        if !envelope.verify() {
+                            continue;
+                        }
+                        let mut envelope = envelope.clone();
+                        envelope.seq = Some(self.history.next_seq());
+                        if self.history.insert(envelope.clone()) {
+                            state.broadcast(Payload::Forward(envelope));
+                        }
+                    }
+                }
+                Payload::Leave => {
+                    if let Some(peer) = state.remove(con_id) {
+                        log::info!("{} left", peer.username());
+                        notify(
+                            &mut self.history,
+                            state,
+                            &self.peer,
+                            format!("{} left", peer.username()),
+                        );
+                        publish_directory(&mut self.keys, state, &self.peer);
+                    }
+                }
+                Payload::SignedKey(key)
+                    if state.peers.get(&con_id).is_some_and(|p| key.verify(p.id())) =>
+                {
+                    state.keys.insert(con_id, *key);
+                    publish_directory(&mut self.keys, state, &self.peer);
+                }
+                Payload::KeyHandout(handout) => {
+                    // Only the keeper hands out group keys
+                    let keeper = state.directory().into_iter().next().map(|(p, _)| p);
+                    match keeper {
+                        Some(keeper)
+                            if state.peers.get(&con_id) == Some(&keeper)
+                                && handout.verify(keeper.id()) =>
+                        {
+                            self.keys.accept(&self.peer, handout);
+                            state.broadcast(Payload::KeyHandout(handout.clone()));
+                        }
+                        _ => log::warn!("Ignoring group key from connection {con_id}"),
+                    }
+                }
+                Payload::Rename(username) => {
+                    if let Some(peer) = state.peers.get_mut(&con_id) {
+                        let text = format!("{} is now known as {username}", peer.username());
+                        peer.set_username(username.clone());
+                        let peer = peer.clone();
+                        state.room.hierarchy.update(&peer);
+                        state.announce();
+                        state.sync_all();
+                        notify(&mut self.history, state, &self.peer, text);
+                    }
                 }
+                Payload::Topic(topic) => match state.peers.get(&con_id) {
+                    Some(peer) if !state.moderation.is_muted(peer.id()) => {
+                        let text = format!("{} set the topic to: {topic}", peer.username());
+                        state.set_topic(topic.clone());
+                        notify(&mut self.history, state, &self.peer, text);
+                    }
+                    _ => log::info!("Ignoring topic from connection {con_id}"),
+                },
                 payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
             },
-            (State::Admin(state), Event::Open(sender)) => {
-                state.clients.push(sender);
+            (State::Admin(state), Event::Beacon(beacon, addr))
+                if beacon.id == state.room.id && beacon.admin != self.peer =>
+            {
+                // Another admin leads our room; only the lower ranked side acts, and only on
+                // the word of the other admin itself, which it proves again once dialed
+                if beacon.verify() && beacon.outranks(&Beacon::from_room(&state.room)) {
+                    let term = bounded_term(state.room.term, beacon.term).max(state.room.term);
+                    // The winner is reached over the interface its beacon came in on
+                    if let SocketAddr::V6(addr) = addr {
+                        self.scope = addr.scope_id();
+                    }
+                    self.merge_into(beacon.admin, term);
+                }
+            }
+            (State::Admin(state), Event::Open(sender, con_id, _)) => {
+                state.clients.insert(con_id, sender);
             }
             (State::Admin(state), Event::Closed(con_id)) => {
-                let closed_peer = state.peers.remove(&con_id).unwrap();
-                state.room.hierarchy.remove(&closed_peer);
-                state.clients.retain(|c| c.connection_id() != con_id);
-
-                let msg = Message::new(Payload::Sync(state.room.clone()));
-                let msg_vec = to_allocvec(&msg).unwrap();
-                state
-                    .clients
-                    .iter()
-                    .for_each(|s| s.send(msg_vec.clone()).unwrap());
+                if let Some(peer) = state.remove(con_id) {
+                    let text = format!("{} lost connection", peer.username());
+                    notify(&mut self.history, state, &self.peer, text);
+                    publish_directory(&mut self.keys, state, &self.peer);
+                }
+            }
+            (State::Admin(state), Event::SubmitMessage(_)) if state.own_key.is_none() => {
+                let text = "You relay this room without its key, so you can't write to it";
+                self.history.error(text.to_string());
             }
             (State::Admin(state), Event::SubmitMessage(payload)) => {
-                self.history.push((self.peer.clone(), payload.clone()));
-                let msg = Message::new(Payload::Forward(self.peer.clone(), payload));
-                let msg_vec = to_allocvec(&msg).unwrap();
-                state
-                    .clients
-                    .iter()
-                    .for_each(|s| s.send(msg_vec.clone()).unwrap());
+                match self.keys.seal(&payload) {
+                    Some(sealed) => {
+                        let mut envelope = Envelope::new(self.peer.clone(), sealed);
+                        envelope.seq = Some(self.history.next_seq());
+                        self.history.insert(envelope.clone());
+                        state.broadcast(Payload::Forward(envelope));
+                    }
+                    None => self.hold(payload),
+                }
             }
-            (State::Member(state), Event::SubmitMessage(payload)) => {
-                let msg = Message::new(Payload::Forward(self.peer.clone(), payload));
-                let msg_vec = to_allocvec(&msg).unwrap();
-                state.admin.send(msg_vec).unwrap();
-            }
-            (State::Member(state), Event::Closed(con_id)) => {
-                if state.admin.connection_id() == con_id {
-                    log::info!("{:?}", state.room.hierarchy);
-
-                    let new_admin = state.room.hierarchy.next_leader().unwrap();
-                    let endpoint = format!("ws://{}:57185", new_admin.addr());
-
-                    if *new_admin == self.peer {
-                        log::info!("Promoting self to admin");
-                        self.state = State::Admin(AdminState {
-                            room: state.room.clone(),
-                            clients: Vec::new(),
-                            peers: HashMap::new(),
-                        })
-                    } else {
-                        log::info!("Connecting to new admin @ {}", new_admin.addr());
-                        connect(endpoint, |out| {
-                            let msg = Message::new(Payload::JoinReq(self.peer.clone()));
-                            let msg_vec = to_allocvec(&msg).unwrap();
-                            out.send(msg_vec.as_slice()).unwrap();
-
-                            Handler::new(self.events_tx.clone(), out.connection_id())
-                        })
-                        .unwrap();
-                    }
-                }
-            }
-            (State::Member(state), Event::Message(msg, _con_id)) => match msg.payload {
-                Payload::Sync(room) => {
-                    log::info!("resyncing state...");
-                    state.room = room
-                }
-                Payload::Forward(peer, payload) => self.history.push((peer, payload)),
-                payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
+            (State::Admin(state), Event::CreateInvite) => match &mut state.protection {
+                Some(protection) => {
+                    let code = protection.invite(state.room.id);
+                    log::info!("Created invite code {code}");
+                    self.history.note(format!("Created invite code {code}"));
+                }
+                None => self
+                    .history
+                    .error("Invite codes are only for protected rooms".to_string()),
             },
+            (_, Event::CreateInvite) => {
+                self.history
+                    .error("Only the admin of the room can create invite codes".to_string());
+            }
+            (State::Admin(state), Event::Moderate(sanction, target)) => {
+                match state.moderate(sanction, &target, &self.peer, &self.remote_addrs) {
+                    Ok(text) => {
+                        log::info!("{text}");
+                        notify(&mut self.history, state, &self.peer, text);
+                        if matches!(sanction, Sanction::Kick | Sanction::Ban) {
+                            publish_directory(&mut self.keys, state, &self.peer);
+                        }
+                    }
+                    Err(e) => self.history.error(e),
+                }
+            }
+            (_, Event::Moderate(sanction, _)) => {
+                self.history
+                    .error(format!("Only the admin of the room can {sanction}"));
+            }
+            (_, Event::Leave) => self.leave(AfterLeave::Quit),
+            (_, Event::Part) => self.leave(AfterLeave::Lobby),
+            (State::Admin(_) | State::Member(_), Event::JoinSend(endpoint)) => {
+                self.leave(AfterLeave::Join(endpoint))
+            }
+            (_, Event::Rename(username)) => self.rename(username),
+            (State::Admin(state), Event::SetTopic(topic)) => {
+                let text = format!("{} set the topic to: {topic}", self.peer.username());
+                state.set_topic(topic);
+                notify(&mut self.history, state, &self.peer, text);
+            }
+            (State::Member(_), Event::SetTopic(_)) if self.moderation.is_muted(self.peer.id()) => {
+                self.history.error("You are muted in this room".to_string());
+            }
+            (State::Member(state), Event::SetTopic(topic)) => {
+                tell_admin(&mut self.history, &state.admin, Payload::Topic(topic))
+            }
+            // Connections of a room we left for the lobby winding down
+            (State::Initial | State::Discover(_), Event::Closed(_)) => {}
+            (State::Leaving(state), Event::Closed(con_id)) => {
+                state.waiting.remove(&con_id);
+            }
+            (State::Member(_), Event::SubmitMessage(_))
+                if self.moderation.is_muted(self.peer.id()) =>
+            {
+                self.history.error("You are muted in this room".to_string());
+            }
+            (State::Member(state), Event::SubmitMessage(payload)) => {
+                match self.keys.seal(&payload) {
+                    Some(sealed) => tell_admin(
+                        &mut self.history,
+                        &state.admin,
+                        Payload::Forward(Envelope::new(self.peer.clone(), sealed)),
+                    ),
+                    None => self.hold(payload),
+                }
+            }
+            (State::Member(state), Event::Closed(con_id)) if state.admin_id == con_id => {
+                log::info!(
+                    "Lost admin, electing a new one from {:?}",
+                    state.room.hierarchy
+                );
+
+                if let Some(admin) = state.room.hierarchy.admin() {
+                    self.history.error(format!(
+                        "Lost connection to {}, electing a new admin",
+                        admin.username()
+                    ));
+                }
+                let room = state.room.clone();
+                let candidates = room.hierarchy.successors().cloned().collect();
+                self.elect(
+                    room.clone(),
+                    room.term.saturating_add(1),
+                    candidates,
+                    Vec::new(),
+                );
+            }
+            (State::Member(state), Event::Message(msg, con_id)) => {
+                // Anyone may connect to us to elect us, but only the admin speaks for the room
+                let from_admin = state.admin_id == con_id;
+                match msg.payload {
+                    Payload::Sync(room) if from_admin && room.term >= state.room.term => {
+                        log::info!("resyncing state...");
+                        state.room = room
+                    }
+                    Payload::Forward(envelope) if from_admin && envelope.verify() => {
+                        self.history.insert(envelope);
+                    }
+                    Payload::Forward(envelope) if from_admin => {
+                        log::warn!("Dropping message {} with a bad signature", envelope.id);
+                    }
+                    Payload::History(entries) if from_admin => {
+                        log::info!("Catching up on {} messages", entries.len());
+                        for envelope in entries.into_iter().filter(Envelope::verify) {
+                            self.history.insert(envelope);
+                        }
+                    }
+                    Payload::SignedDirectory(directory) if from_admin => {
+                        if let Some(handout) = self.keys.rekey(&self.peer, &directory) {
+                            self.keys.accept(&self.peer, &handout);
+                            let handout = Payload::KeyHandout(handout);
+                            tell_admin(&mut self.history, &state.admin, handout);
+                        }
+                    }
+                    Payload::KeyHandout(handout) if from_admin => {
+                        self.keys.accept(&self.peer, &handout)
+                    }
+                    Payload::HistoryWant(ids) if from_admin => {
+                        let entries = Payload::History(self.history.with_ids(&ids));
+                        tell_admin(&mut self.history, &state.admin, entries);
+                    }
+                    Payload::Moderation(moderation) if from_admin => self.moderation = moderation,
+                    Payload::Topic(topic) if from_admin => self.topic = Some(topic),
+                    Payload::Reject(reason) if from_admin => {
+                        // Kicked or banned, rather than having lost the admin
+                        log::warn!("Removed from the room: {reason}");
+                        let _ = state.admin.close(CloseCode::Normal);
+                        self.rejection = Some(reason);
+                        self.state = State::Initial;
+                        queue(&self.events_tx, Event::Discover);
+                    }
+                    Payload::Elect(_, peer, _) if !from_admin => {
+                        // We still have an admin, so the peer is pointed back at it rather than
+                        // handed the room
+                        log::info!(
+                            "{} asked us to lead, pointing it at the admin",
+                            peer.username()
+                        );
+                        let admin = state.room.hierarchy.admin().cloned();
+                        if let (Some(admin), Some(voter)) = (admin, self.pending.get(&con_id)) {
+                            let mandate = Mandate::new(state.room.id, state.room.term, admin);
+                            if let Err(e) = send(voter, Payload::Leader(mandate)) {
+                                log::warn!("Failed to redirect {con_id}: {e}");
+                            }
+                        }
+                    }
+                    Payload::Leader(mandate)
+                        if from_admin
+                            && state.room.hierarchy.admin().is_some_and(|admin| {
+                                mandate.is_from(admin.id(), state.room.id)
+                            })
+                            && mandate.term >= state.room.term =>
+                    {
+                        let term = bounded_term(state.room.term, mandate.term);
+                        let leader = mandate.leader;
+                        log::info!("Admin handed over to {} for term {term}", leader.username());
+                        let _ = state.admin.close(CloseCode::Normal);
+                        let room = state.room.clone();
+                        self.elect(room, term, VecDeque::from([leader]), Vec::new());
+                    }
+                    payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
+                }
+            }
+            (State::Electing(state), Event::Connected(sender, con_id, endpoint))
+                if state
+                    .target
+                    .as_ref()
+                    .is_some_and(|t| t.endpoint() == endpoint) =>
+            {
+                state.link = Some((con_id, sender));
+            }
+            (State::Electing(state), Event::ConnectFailed(endpoint))
+                if state
+                    .target
+                    .as_ref()
+                    .is_some_and(|t| t.endpoint() == endpoint) =>
+            {
+                log::info!("Candidate @ {endpoint} is unreachable");
+                self.next_candidate();
+            }
+            (State::Electing(state), Event::Closed(con_id))
+                if state.link.as_ref().is_some_and(|(id, _)| *id == con_id) =>
+            {
+                state.link = None;
+                self.next_candidate();
+            }
+            (State::Electing(state), Event::Message(msg, con_id)) => {
+                let from_link = state.link.as_ref().is_some_and(|(id, _)| *id == con_id);
+                match msg.payload {
+                    Payload::Sync(room) if from_link && state.verified => {
+                        let Some((admin_id, admin)) = state.link.take() else {
+                            return;
+                        };
+                        log::info!("Elected admin for term {} accepted us", room.term);
+
+                        // Point anyone who was waiting on us at the new admin
+                        if let Some(leader) = room.hierarchy.admin() {
+                            if let Some(merged) = state.merging.take() {
+                                let mandate = Mandate::new(room.id, room.term, leader.clone());
+                                merged.broadcast(Payload::Leader(mandate));
+                            }
+                            for (voter_id, ..) in &state.voters {
+                                if let Some(voter) = self.pending.get(voter_id) {
+                                    let mandate = Mandate::new(room.id, room.term, leader.clone());
+                                    let redirect = Payload::Leader(mandate);
+                                    if let Err(e) = send(voter, redirect) {
+                                        log::warn!("Failed to redirect {voter_id}: {e}");
+                                    }
+                                }
+                            }
+                        }
+
+                        self.history.attach(&room);
+                        self.keys.attach(room.id);
+                        request_history(&mut self.history, &admin);
+                        let key = Payload::SignedKey(self.keys.public());
+                        tell_admin(&mut self.history, &admin, key);
+                        self.state = State::Member(MemberState {
+                            room,
+                            admin,
+                            admin_id,
+                        });
+                    }
+                    Payload::Leader(mandate)
+                        if from_link
+                            && state.target.as_ref().is_some_and(|target| {
+                                mandate.is_from(target.id(), state.room.id)
+                            }) =>
+                    {
+                        let leader = mandate.leader;
+                        log::info!(
+                            "Redirected to {} for term {}",
+                            leader.username(),
+                            mandate.term
+                        );
+                        state.term = state.term.max(bounded_term(state.room.term, mandate.term));
+                        state.candidates.push_front(leader);
+                        self.next_candidate();
+                    }
+                    Payload::Reject(reason) if from_link => {
+                        log::warn!("Candidate rejected us: {reason}");
+                        self.next_candidate();
+                    }
+                    Payload::Challenge(room_id, nonce) if from_link && state.verified => {
+                        match credential(self.room_key, None, room_id) {
+                            Some(key) => {
+                                let proof = Payload::Proof(access::prove(&key, &nonce));
+                                let sent = state.link.as_ref().map(|(_, link)| send(link, proof));
+                                if let Some(Err(e)) = sent {
+                                    log::warn!("Failed to answer candidate's challenge: {e}");
+                                }
+                            }
+                            None => {
+                                log::warn!("Candidate challenged us, but we have no room key");
+                                self.next_candidate();
+                            }
+                        }
+                    }
+                    Payload::Identify(room_id, admin, nonce, signature) if from_link => {
+                        // Only ever prove who we are to the candidate we meant to ask
+                        let target = state.target.as_ref().map(Peer::id);
+                        if room_id != state.room.id || target != Some(&admin) {
+                            log::warn!("Candidate asked who we are for another room or admin");
+                            self.next_candidate();
+                            return;
+                        }
+                        let tls = self.tls.as_deref();
+                        let checked =
+                            check_certificate(tls, con_id, room_id, &admin, &nonce, &signature);
+                        if let Err(rejection) = checked {
+                            log::error!("{rejection}");
+                            self.history.error(rejection);
+                            self.next_candidate();
+                            return;
+                        }
+                        state.verified = true;
+                        let proof = Identity::local().prove(&nonce, room_id, &admin);
+                        let proof = Payload::IdentityProof(proof);
+                        let sent = state.link.as_ref().map(|(_, link)| send(link, proof));
+                        if let Some(Err(e)) = sent {
+                            log::warn!("Failed to prove our identity to the candidate: {e}");
+                        }
+                    }
+                    // We already hold the room key
+                    Payload::Admit(_) if from_link => {}
+                    // Only peers of the room we lost the admin of may ask us to lead it
+                    Payload::Elect(term, peer, capabilities)
+                        if state.room.hierarchy.0.contains(&peer) =>
+                    {
+                        state.term = state.term.max(bounded_term(state.room.term, term));
+                        state.voters.push((con_id, peer, capabilities));
+                    }
+                    payload => log::warn!("No transition for ({:?}, {payload:?})", self.state),
+                }
+            }
+            (State::Admin(_) | State::Member(_), Event::Error(error)) => self.history.error(error),
+            (_, Event::Error(error)) => self.error = Some(error),
+            // Late discovery events, e.g. after leaving the lobby
+            (_, Event::Beacon(..) | Event::Latency(..)) => {}
+            (_, Event::Open(sender, con_id, _)) => {
+                self.pending.insert(con_id, sender);
+            }
             (_, evt) => log::warn!("No transition for ({:?}, {evt:?})", self.state),
         };
     }
@@ -168,32 +1428,403 @@ pub enum State {
     Initial,
     Discover(DiscoverState),
     Connect(ConnectState),
-    Admin(AdminState),
+    Admin(Box<AdminState>),
     Member(MemberState),
-    Leaving,
+    /// Looking for a new admin after the previous one was lost
+    Electing(ElectingState),
+    /// Left the room, waiting for our goodbyes to go out before shutting down
+    Leaving(LeavingState),
 }
 
 #[derive(Debug, Clone)]
-pub struct DiscoverState {}
+pub struct DiscoverState {
+    rooms: Vec<DiscoveredRoom>,
+    _listener: Listener,
+}
+
+/// A room whose beacon was recently received
+#[derive(Debug, Clone)]
+pub struct DiscoveredRoom {
+    pub beacon: Beacon,
+    /// Address the beacon was sent from, which is where the admin can be reached
+    pub addr: IpAddr,
+    /// Interface the beacon came in on, if the address is link-local
+    pub scope: u32,
+    pub last_seen: Instant,
+    pub latency: Option<Duration>,
+}
+
+impl DiscoveredRoom {
+    /// Where to join the room, on the port and slot its admin announced
+    pub fn endpoint(&self) -> Endpoint {
+        Endpoint {
+            addr: self.addr,
+            scope: self.scope,
+            ..self.beacon.admin.endpoint()
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ConnectState {
     admin: WsSender,
+    admin_id: u32,
+    /// Room id and nonce the admin challenged us with, if the room is protected
+    challenge: Option<(u64, Nonce)>,
+    /// Whether we sent a proof for the challenge
+    answered: bool,
+    /// Room id and admin that vouched for the connection, which the room we join has to be
+    verified: Option<(u64, PeerId)>,
 }
 
 #[derive(Debug, Clone)]
 pub struct AdminState {
     room: Room,
-    clients: Vec<WsSender>,
+    clients: HashMap<u32, WsSender>,
     peers: HashMap<u32, Peer>,
+    /// What each joined client understands, so we never send it payloads it can't decode
+    capabilities: HashMap<u32, Capabilities>,
+    /// Public keys the joined clients published
+    keys: HashMap<u32, SignedKey>,
+    /// Our own key in the directory, unless we relay without being able to read the room
+    own_key: Option<SignedKey>,
+    /// Secrets joiners have to prove knowledge of, if the room is protected
+    protection: Option<Protection>,
+    /// Ids the clients proved to hold the keys of
+    identified: HashMap<u32, PeerId>,
+    /// Nonces sent to clients to prove the id they claimed with, along with their requests
+    identifying: HashMap<u32, (Nonce, Payload)>,
+    /// Messages we asked each client for, which it held while we didn't
+    wanted: HashMap<u32, HashSet<MessageId>>,
+    moderation: Moderation,
+    topic: Option<String>,
+    announcer: Option<Announcer>,
+    /// Listens for other admins of the same room, which happens after a network split
+    _listener: Option<Listener>,
+    events_tx: ChSender<Event>,
 }
 
 impl AdminState {
-    pub fn new() -> Self {
+    /// State of a new room led by `admin`
+    pub fn new(admin: Peer, events_tx: ChSender<Event>) -> Self {
+        Self::from_room(Room::new(admin), events_tx)
+    }
+
+    pub fn from_room(room: Room, events_tx: ChSender<Event>) -> Self {
+        let announcer = Announcer::spawn(Beacon::from_room(&room))
+            .inspect_err(|e| log::error!("Failed to start room announcer: {e}"))
+            .ok();
+        let listener = Listener::spawn(events_tx.clone())
+            .inspect_err(|e| log::error!("Failed to start split detection: {e}"))
+            .ok();
         AdminState {
-            room: Room::new(),
-            clients: Vec::new(),
+            room,
+            clients: HashMap::new(),
             peers: HashMap::new(),
+            capabilities: HashMap::new(),
+            keys: HashMap::new(),
+            own_key: None,
+            protection: None,
+            identified: HashMap::new(),
+            identifying: HashMap::new(),
+            wanted: HashMap::new(),
+            moderation: Moderation::default(),
+            topic: None,
+            announcer,
+            _listener: listener,
+            events_tx,
+        }
+    }
+
+    /// Add `peer`, connected on `con_id`, to the room
+    fn accept(&mut self, con_id: u32, peer: Peer, capabilities: Capabilities) {
+        self.room.hierarchy.push(peer.clone());
+        self.peers.insert(con_id, peer);
+        self.capabilities.insert(con_id, capabilities);
+        self.announce();
+        self.sync_all();
+        self.send_to(con_id, Payload::Moderation(self.moderation.clone()));
+        if let Some(topic) = &self.topic {
+            self.send_to(con_id, Payload::Topic(topic.clone()));
+        }
+    }
+
+    fn set_topic(&mut self, topic: String) {
+        self.topic = Some(topic.clone());
+        self.broadcast(Payload::Topic(topic));
+    }
+
+    /// Drop the client on `con_id` from the room, returning the peer that had joined on it
+    fn remove(&mut self, con_id: u32) -> Option<Peer> {
+        self.forget(con_id);
+        self.clients.remove(&con_id);
+        self.capabilities.remove(&con_id);
+        self.keys.remove(&con_id);
+        let peer = self.peers.remove(&con_id)?;
+        self.room.hierarchy.remove(&peer);
+        self.announce();
+        self.sync_all();
+        Some(peer)
+    }
+
+    /// Apply `sanction`, which we as `me` imposed, to the peer named by `target`, returning the
+    /// notice to post in the room
+    fn moderate(
+        &mut self,
+        sanction: Sanction,
+        target: &str,
+        me: &Peer,
+        remote_addrs: &HashMap<u32, IpAddr>,
+    ) -> Result<String, String> {
+        let peer = match sanction {
+            Sanction::Unban => {
+                moderation::resolve(self.moderation.banned().map(|ban| &ban.peer), target)?
+            }
+            _ => moderation::resolve(self.room.hierarchy.0.iter(), target)?,
+        }
+        .clone();
+        if peer == *me {
+            return Err(format!("You can't {sanction} yourself"));
+        }
+        let con_id = self
+            .peers
+            .iter()
+            .find(|(_, p)| **p == peer)
+            .map(|(con_id, _)| *con_id);
+
+        let changed = match sanction {
+            Sanction::Kick => true,
+            Sanction::Ban => {
+                let addr = con_id.and_then(|con_id| remote_addrs.get(&con_id)).copied();
+                self.moderation.ban(peer.clone(), addr)
+            }
+            Sanction::Unban => {
+                self.moderation.unban(&peer);
+                true
+            }
+            Sanction::Mute => self.moderation.mute(peer.clone()),
+            Sanction::Unmute => self.moderation.unmute(&peer),
+        };
+        if !changed {
+            return Err(format!(
+                "{} is already {}",
+                peer.username(),
+                sanction.past()
+            ));
+        }
+
+        if matches!(sanction, Sanction::Kick | Sanction::Ban) {
+            match con_id {
+                Some(con_id) => {
+                    let reason = format!("You were {} by {}", sanction.past(), me.username());
+                    self.reject(con_id, &reason);
+                    self.remove(con_id);
+                }
+                None => {
+                    self.room.hierarchy.remove(&peer);
+                    self.announce();
+                    self.sync_all();
+                }
+            }
+        }
+        if sanction != Sanction::Kick {
+            self.broadcast(Payload::Moderation(self.moderation.clone()));
+        }
+        Ok(format!(
+            "{} was {} by {}",
+            peer.username(),
+            sanction.past(),
+            me.username()
+        ))
+    }
+
+    /// Whether `con_id` may take part in the room, which in a protected room means it answered
+    /// its challenge
+    fn admits(&self, con_id: u32) -> bool {
+        self.protection
+            .as_ref()
+            .is_none_or(|protection| protection.admitted(con_id))
+    }
+
+    /// Hold `request` back until `con_id` proves it knows the room's secret
+    fn challenge(&mut self, con_id: u32, request: Payload) {
+        let capabilities = request.claimed_capabilities();
+        if !capabilities.contains(Capabilities::AUTH) {
+            self.reject(
+                con_id,
+                "This room needs a passphrase, which your version can't send",
+            );
+            return;
+        }
+        let Some(protection) = &mut self.protection else {
+            return;
+        };
+
+        log::info!("Challenging connection {con_id}");
+        let nonce = protection.challenge(con_id, request);
+        self.capabilities.insert(con_id, capabilities);
+        self.send_to(con_id, Payload::Challenge(self.room.id, nonce));
+    }
+
+    /// Admit `con_id` if `proof` answers its challenge, going on with the request it made
+    fn check_proof(&mut self, con_id: u32, proof: &[u8; 32], events_tx: &ChSender<Event>) {
+        let Some(protection) = &mut self.protection else {
+            return;
+        };
+        match protection.respond(con_id, proof) {
+            Some((request, sealed)) => {
+                log::info!("Connection {con_id} answered its challenge");
+                self.send_to(con_id, Payload::Admit(sealed));
+                queue(events_tx, Event::Message(Message::new(request), con_id));
+            }
+            None => self.reject(con_id, "Wrong passphrase or invite code"),
+        }
+    }
+
+    /// Whether `con_id` proved it's the peer `request` comes from
+    fn identifies(&self, con_id: u32, request: &Payload) -> bool {
+        request.claimant().is_some() && self.identified.get(&con_id) == request.claimant()
+    }
+
+    /// Hold `request` back until `con_id` proves it holds the key of the id it claims, vouching
+    /// for our certificate with `fingerprint` in turn
+    fn identify(&mut self, con_id: u32, request: Payload, fingerprint: &str) {
+        let capabilities = request.claimed_capabilities();
+        if !capabilities.contains(Capabilities::IDENTITY) {
+            self.reject(
+                con_id,
+                "This room needs proof of who you are, which your version can't send",
+            );
+            return;
+        }
+
+        log::info!("Asking connection {con_id} to prove its identity");
+        let nonce = rand::random();
+        self.identifying.insert(con_id, (nonce, request));
+        self.capabilities.insert(con_id, capabilities);
+        let (room_id, admin) = (self.room.id, Identity::local());
+        let certified = admin.certify(&nonce, room_id, fingerprint);
+        self.send_to(
+            con_id,
+            Payload::Identify(room_id, admin.id(), nonce, certified),
+        );
+    }
+
+    /// Take `con_id` to be the peer it claimed if `proof` answers its nonce, going on with the
+    /// request it made
+    fn check_identity(&mut self, con_id: u32, proof: &Signature, events_tx: &ChSender<Event>) {
+        let Some((nonce, request)) = self.identifying.remove(&con_id) else {
+            return;
+        };
+        match request.claimant() {
+            Some(id)
+                if identity::verify(id, &nonce, self.room.id, &Identity::local().id(), proof) =>
+            {
+                log::info!("Connection {con_id} proved to be {id}");
+                self.identified.insert(con_id, *id);
+                queue(events_tx, Event::Message(Message::new(request), con_id));
+            }
+            _ => self.reject(con_id, "Could not prove who you are"),
+        }
+    }
+
+    /// Turn `con_id` away, telling it why
+    fn reject(&self, con_id: u32, reason: &str) {
+        log::info!("Rejecting connection {con_id}: {reason}");
+        self.send_to(con_id, Payload::Reject(reason.to_string()));
+        if let Some(client) = self.clients.get(&con_id) {
+            let _ = client.close(CloseCode::Policy);
+        }
+    }
+
+    /// Drop access state of a connection that closed or left
+    fn forget(&mut self, con_id: u32) {
+        self.identified.remove(&con_id);
+        self.identifying.remove(&con_id);
+        self.wanted.remove(&con_id);
+        if let Some(protection) = &mut self.protection {
+            protection.forget(con_id);
+        }
+    }
+
+    /// Whether the client on `con_id` understands `payload`
+    fn understands(&self, con_id: u32, payload: &Payload) -> bool {
+        self.capabilities
+            .get(&con_id)
+            .copied()
+            .unwrap_or_default()
+            .contains(payload.requires())
+    }
+
+    fn send_to(&self, con_id: u32, payload: Payload) {
+        if !self.understands(con_id, &payload) {
+            return;
+        }
+        if let Some(client) = self.clients.get(&con_id) {
+            if let Err(e) = send(client, payload) {
+                self.evict(con_id, e);
+            }
+        }
+    }
+
+    /// Send `payload` to every client that understands it
+    fn broadcast(&self, payload: Payload) {
+        let msg_vec = match to_allocvec(&Message::new(payload.clone())) {
+            Ok(msg_vec) => msg_vec,
+            Err(e) => {
+                log::error!("Failed to encode {payload:?}: {e}");
+                return;
+            }
+        };
+        for (con_id, client) in &self.clients {
+            if !self.understands(*con_id, &payload) {
+                continue;
+            }
+            if let Err(e) = client.send(msg_vec.clone()) {
+                self.evict(*con_id, e.into());
+            }
+        }
+    }
+
+    /// Drop a client we can't send to anymore, the same way as if its connection closed
+    fn evict(&self, con_id: u32, error: VlawnError) {
+        log::warn!("Evicting connection {con_id}: {error}");
+        if let Some(client) = self.clients.get(&con_id) {
+            let _ = client.close(CloseCode::Error);
+        }
+        queue(&self.events_tx, Event::Closed(con_id));
+    }
+
+    /// Send the current room to every client
+    fn sync_all(&self) {
+        self.broadcast(Payload::Sync(self.room.clone()));
+    }
+
+    /// Keys of the room's members in hierarchy order, starting with ours unless we're blind
+    fn directory(&self) -> Vec<(Peer, SignedKey)> {
+        let mut directory: Vec<(Peer, SignedKey)> = self
+            .own_key
+            .zip(self.room.hierarchy.admin().cloned())
+            .map(|(key, admin)| (admin, key))
+            .into_iter()
+            .collect();
+        for peer in self.room.hierarchy.successors() {
+            let key = self
+                .peers
+                .iter()
+                .find(|(_, p)| *p == peer)
+                .and_then(|(con_id, _)| self.keys.get(con_id));
+            if let Some(key) = key {
+                directory.push((peer.clone(), *key));
+            }
+        }
+        directory
+    }
+
+    /// Refresh the announced beacon after the room changed
+    fn announce(&self) {
+        if let Some(announcer) = &self.announcer {
+            announcer.update(Beacon::from_room(&self.room));
         }
     }
 }
@@ -202,4 +1833,461 @@ impl AdminState {
 pub struct MemberState {
     room: Room,
     admin: WsSender,
+    admin_id: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ElectingState {
+    room: Room,
+    term: u64,
+    /// Peers still to ask, in order of precedence
+    candidates: VecDeque<Peer>,
+    /// Candidate currently being asked
+    target: Option<Peer>,
+    /// Connection to the target, once open
+    link: Option<(u32, WsSender)>,
+    /// Whether the target vouched for the link, which we say nothing secret on until it has
+    verified: bool,
+    /// Peers that asked us to lead while we were electing
+    voters: Vec<Voter>,
+    /// Side of a split room we led, while merging it into the target's
+    merging: Option<Box<AdminState>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LeavingState {
+    /// Connections that haven't finished closing yet
+    waiting: HashSet<u32>,
+    deadline: Instant,
+}
+
+impl LeavingState {
+    fn new(waiting: HashSet<u32>) -> Self {
+        LeavingState {
+            waiting,
+            deadline: Instant::now() + LEAVE_TIMEOUT,
+        }
+    }
+}
+
+/// What to do once we've said goodbye to a room
+enum AfterLeave {
+    Quit,
+    Lobby,
+    /// Join the room at the given endpoint
+    Join(Endpoint),
+}
+
+/// Connection, identity and capabilities of a peer that asked us to lead
+type Voter = (u32, Peer, Capabilities);
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Once;
+
+    use super::*;
+    use crate::entities::BEACON_PORT;
+
+    /// Someone else in the room, along with the key it signs with
+    struct Other {
+        identity: Identity,
+        peer: Peer,
+    }
+
+    impl Other {
+        fn new(username: &str) -> Self {
+            let identity = Identity::generate();
+            // Nothing listens there, so dialing the peer fails
+            let endpoint = Endpoint::new(IpAddr::from([127, 0, 0, 1]), 9);
+            let peer = Peer::new(username, endpoint, identity.id());
+            Other { identity, peer }
+        }
+    }
+
+    /// Handles nothing, as nothing runs the sockets it's made for
+    struct Idle;
+
+    impl ws::Handler for Idle {}
+
+    /// A connection nothing reads from, so what's sent on it just queues up
+    fn sender() -> WsSender {
+        let socket = ws::WebSocket::new(|_| Idle).unwrap();
+        let sender = socket.broadcaster();
+        // Sending fails once the socket is gone
+        std::mem::forget(socket);
+        sender
+    }
+
+    /// A room led by `admin`, followed by `members`
+    fn room(admin: &Peer, members: &[&Peer]) -> Room {
+        let mut room = Room::new(admin.clone());
+        for member in members {
+            room.hierarchy.push((*member).clone());
+        }
+        room
+    }
+
+    /// A state machine driven by hand, the tests playing every peer it talks to
+    struct Harness {
+        manager: StateManager,
+        events_rx: Receiver<Event>,
+        next_con_id: u32,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            // Rooms we get into are stored away from the user's
+            static DATA_HOME: Once = Once::new();
+            DATA_HOME.call_once(|| {
+                std::env::set_var("XDG_DATA_HOME", std::env::temp_dir().join("vlawn-tests"))
+            });
+            let (events_tx, events_rx) = crossbeam_channel::unbounded();
+            Harness {
+                manager: StateManager::new(events_tx, Heartbeat::default(), None),
+                events_rx,
+                next_con_id: 0,
+            }
+        }
+
+        fn me(&self) -> Peer {
+            self.manager.peer.clone()
+        }
+
+        /// Hand `event` to the state machine, along with the messages it queues for itself in
+        /// turn. How dialing goes is up to the tests, so whatever else it queues is dropped.
+        fn handle(&mut self, event: Event) {
+            self.manager.handle(event);
+            while let Ok(event) = self.events_rx.try_recv() {
+                if let Event::Message(..) = event {
+                    self.manager.handle(event);
+                }
+            }
+        }
+
+        fn con_id(&mut self) -> u32 {
+            self.next_con_id += 1;
+            self.next_con_id
+        }
+
+        /// A connection someone opened to us
+        fn open(&mut self) -> u32 {
+            let con_id = self.con_id();
+            self.handle(Event::Open(sender(), con_id, None));
+            con_id
+        }
+
+        fn send(&mut self, con_id: u32, payload: Payload) {
+            self.handle(Event::Message(Message::new(payload), con_id));
+        }
+
+        /// Have `other` answer the nonce we sent `con_id` to prove who it is
+        fn prove(&mut self, con_id: u32, other: &Other) {
+            let state = self.admin();
+            let (nonce, _) = state.identifying[&con_id];
+            let proof = other
+                .identity
+                .prove(&nonce, state.room.id, &Identity::local().id());
+            self.send(con_id, Payload::IdentityProof(proof));
+        }
+
+        /// Have `other` join the room we lead
+        fn join(&mut self, other: &Other) -> u32 {
+            let con_id = self.open();
+            self.send(
+                con_id,
+                Payload::JoinReq(other.peer.clone(), Capabilities::local()),
+            );
+            self.prove(con_id, other);
+            con_id
+        }
+
+        /// Have `link`, the connection we dialed to `other`, vouch for it for `room_id`
+        fn vouch(&mut self, link: u32, other: &Other, room_id: u64) {
+            let nonce = rand::random();
+            let signature = other.identity.certify(&nonce, room_id, "");
+            let identify = Payload::Identify(room_id, other.identity.id(), nonce, signature);
+            self.send(link, identify);
+        }
+
+        /// Join `room` through its admin `admin`, returning the connection to it
+        fn join_room(&mut self, admin: &Other, room: Room) -> u32 {
+            let con_id = self.con_id();
+            self.handle(Event::Connected(sender(), con_id, admin.peer.endpoint()));
+            self.vouch(con_id, admin, room.id);
+            self.send(con_id, Payload::Sync(room));
+            con_id
+        }
+
+        fn admin(&self) -> &AdminState {
+            match &self.manager.state {
+                State::Admin(state) => state,
+                state => panic!("Expected to lead the room, but {state:?}"),
+            }
+        }
+
+        fn member(&self) -> &MemberState {
+            match &self.manager.state {
+                State::Member(state) => state,
+                state => panic!("Expected to be a member, but {state:?}"),
+            }
+        }
+
+        fn electing(&self) -> &ElectingState {
+            match &self.manager.state {
+                State::Electing(state) => state,
+                state => panic!("Expected to be electing, but {state:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn the_next_in_line_takes_over_a_lost_admin() {
+        let mut h = Harness::new();
+        let (ada, bob) = (Other::new("ada"), Other::new("bob"));
+        let room = room(&ada.peer, &[&h.me(), &bob.peer]);
+        let admin_id = h.join_room(&ada, room.clone());
+        assert_eq!(h.member().room.id, room.id);
+
+        h.handle(Event::Closed(admin_id));
+        let state = h.admin();
+        assert_eq!((state.room.id, state.room.term), (room.id, room.term + 1));
+        assert_eq!(state.room.hierarchy.admin(), Some(&h.me()));
+    }
+
+    #[test]
+    fn elections_go_down_the_hierarchy() {
+        let mut h = Harness::new();
+        let (ada, bob) = (Other::new("ada"), Other::new("bob"));
+        let room = room(&ada.peer, &[&bob.peer, &h.me()]);
+        let admin_id = h.join_room(&ada, room.clone());
+
+        h.handle(Event::Closed(admin_id));
+        let state = h.electing();
+        assert_eq!(state.target.as_ref(), Some(&bob.peer));
+        assert_eq!(state.term, room.term + 1);
+
+        // Bob is unreachable, which leaves us
+        h.handle(Event::ConnectFailed(bob.peer.endpoint()));
+        assert_eq!(h.admin().room.term, room.term + 1);
+    }
+
+    #[test]
+    fn elections_only_follow_a_candidate_that_vouched_for_itself() {
+        let mut h = Harness::new();
+        let (ada, bob, eve) = (Other::new("ada"), Other::new("bob"), Other::new("eve"));
+        let room = room(&ada.peer, &[&bob.peer, &h.me()]);
+        let admin_id = h.join_room(&ada, room.clone());
+        h.handle(Event::Closed(admin_id));
+
+        let link = h.con_id();
+        h.handle(Event::Connected(sender(), link, bob.peer.endpoint()));
+        let mut theirs = self::room(&bob.peer, &[&h.me()]);
+        (theirs.id, theirs.term) = (room.id, room.term + 1);
+        h.send(link, Payload::Sync(theirs.clone()));
+        assert!(!h.electing().verified);
+
+        h.vouch(link, &bob, room.id);
+        h.send(link, Payload::Sync(theirs));
+        assert_eq!(h.member().room.hierarchy.admin(), Some(&bob.peer));
+
+        // Someone else answering where we dialed bob is given up on
+        let mut h = Harness::new();
+        let admin_id = h.join_room(&ada, self::room(&ada.peer, &[&bob.peer, &h.me()]));
+        h.handle(Event::Closed(admin_id));
+        let link = h.con_id();
+        h.handle(Event::Connected(sender(), link, bob.peer.endpoint()));
+        h.vouch(link, &eve, room.id);
+        h.admin();
+    }
+
+    #[test]
+    fn members_cant_make_the_admin_step_down() {
+        let mut h = Harness::new();
+        h.handle(Event::StartRoom(RoomOptions::default()));
+        let bob = Other::new("bob");
+        let bob_id = h.join(&bob);
+        let (room_id, term) = (h.admin().room.id, h.admin().room.term);
+        let mandate = |term| Mandate::signed_by(&bob.identity, room_id, term, bob.peer.clone());
+
+        // Not on the connection it's a member on
+        h.send(bob_id, Payload::Leader(mandate(term + 1)));
+        h.admin();
+        // Nor for any term but the next
+        let con_id = h.open();
+        h.send(con_id, Payload::Leader(mandate(u64::MAX)));
+        h.admin();
+        // Nor by anyone outside the room
+        let eve = Other::new("eve");
+        let con_id = h.open();
+        let claim = Mandate::signed_by(&eve.identity, room_id, term + 1, eve.peer.clone());
+        h.send(con_id, Payload::Leader(claim));
+        h.admin();
+
+        // Only a peer of the room that won the next term on its own
+        let con_id = h.open();
+        h.send(con_id, Payload::Leader(mandate(term + 1)));
+        let state = h.electing();
+        assert_eq!(state.target.as_ref(), Some(&bob.peer));
+        assert_eq!(state.term, term + 1);
+    }
+
+    #[test]
+    fn claimed_terms_only_ever_go_up_by_one() {
+        let mut h = Harness::new();
+        h.handle(Event::StartRoom(RoomOptions::default()));
+        let bob = Other::new("bob");
+        let con_id = h.open();
+        let elect = Payload::Elect(u64::MAX, bob.peer.clone(), Capabilities::local());
+        h.send(con_id, elect);
+        h.prove(con_id, &bob);
+
+        let state = h.admin();
+        assert!(state.room.hierarchy.0.contains(&bob.peer));
+        assert_eq!(state.room.term, 1);
+    }
+
+    #[test]
+    fn members_only_follow_their_admins_word() {
+        let mut h = Harness::new();
+        let (ada, bob) = (Other::new("ada"), Other::new("bob"));
+        let room = room(&ada.peer, &[&h.me(), &bob.peer]);
+        let admin_id = h.join_room(&ada, room.clone());
+        let by_bob = Mandate::signed_by(&bob.identity, room.id, room.term + 1, bob.peer.clone());
+
+        // Bob dialing us to elect us or to claim the room
+        let con_id = h.open();
+        let elect = Payload::Elect(room.term + 1, bob.peer.clone(), Capabilities::local());
+        h.send(con_id, elect);
+        h.send(con_id, Payload::Leader(by_bob.clone()));
+        assert_eq!(h.member().room.term, room.term);
+        // Nor is bob's word passed on by the admin's
+        h.send(admin_id, Payload::Leader(by_bob));
+        assert_eq!(h.member().room.term, room.term);
+
+        // The admin handing over, however far ahead it claims the term to be
+        let handover = Mandate::signed_by(&ada.identity, room.id, u64::MAX, bob.peer.clone());
+        h.send(admin_id, Payload::Leader(handover));
+        let state = h.electing();
+        assert_eq!(state.target.as_ref(), Some(&bob.peer));
+        assert_eq!(state.term, room.term + 1);
+    }
+
+    #[test]
+    fn leaving_admins_hand_over() {
+        let mut h = Harness::new();
+        let (ada, bob) = (Other::new("ada"), Other::new("bob"));
+        let room = room(&ada.peer, &[&h.me(), &bob.peer]);
+        let admin_id = h.join_room(&ada, room.clone());
+
+        let handover = Mandate::signed_by(&ada.identity, room.id, room.term + 1, h.me());
+        h.send(admin_id, Payload::Leader(handover));
+        let state = h.admin();
+        assert_eq!((state.room.id, state.room.term), (room.id, room.term + 1));
+
+        // In turn, we wait on our clients to be told before going
+        let bob_id = h.join(&bob);
+        h.handle(Event::Leave);
+        assert!(!h.manager.has_left());
+        h.handle(Event::Closed(bob_id));
+        assert!(h.manager.has_left());
+    }
+
+    #[test]
+    fn splits_only_merge_into_the_admin_that_signed_its_beacon() {
+        let mut h = Harness::new();
+        h.handle(Event::StartRoom(RoomOptions::default()));
+        let ada = Other::new("ada");
+        let mut theirs = h.admin().room.clone();
+        theirs.hierarchy = Hierarchy(vec![ada.peer.clone()]);
+        theirs.term = u64::MAX;
+        let addr = SocketAddr::from(([127, 0, 0, 1], BEACON_PORT));
+
+        // Naming ada, but signed by someone else
+        h.handle(Event::Beacon(Beacon::from_room(&theirs), addr));
+        h.admin();
+        // Signed by ada, but not for what it claims
+        let mut tampered = Beacon::signed_by(&ada.identity, &h.admin().room.clone());
+        tampered.admin = ada.peer.clone();
+        h.handle(Event::Beacon(tampered, addr));
+        h.admin();
+
+        h.handle(Event::Beacon(
+            Beacon::signed_by(&ada.identity, &theirs),
+            addr,
+        ));
+        let state = h.electing();
+        assert_eq!(state.target.as_ref(), Some(&ada.peer));
+        assert_eq!(state.term, 1);
+        assert!(state.merging.is_some());
+
+        // Ada can't be reached, so we go on leading our side
+        h.handle(Event::ConnectFailed(ada.peer.endpoint()));
+        assert_eq!(h.admin().room.id, theirs.id);
+
+        // Once ada vouched for the link and took us in, we follow it
+        h.handle(Event::Beacon(
+            Beacon::signed_by(&ada.identity, &theirs),
+            addr,
+        ));
+        let link = h.con_id();
+        h.handle(Event::Connected(sender(), link, ada.peer.endpoint()));
+        h.vouch(link, &ada, theirs.id);
+        theirs.hierarchy.push(h.me());
+        theirs.term = 1;
+        h.send(link, Payload::Sync(theirs));
+        assert_eq!(h.member().room.hierarchy.admin(), Some(&ada.peer));
+    }
+
+    #[test]
+    fn takes_in_the_other_side_of_a_split() {
+        let mut h = Harness::new();
+        h.handle(Event::StartRoom(RoomOptions::default()));
+        let ada = Other::new("ada");
+        let mut theirs = h.admin().room.clone();
+        theirs.hierarchy = Hierarchy(vec![ada.peer.clone()]);
+        theirs.term = u64::MAX;
+
+        let con_id = h.open();
+        h.send(con_id, Payload::Merge(theirs));
+        assert!(!h.admin().room.hierarchy.0.contains(&ada.peer));
+        h.prove(con_id, &ada);
+
+        let state = h.admin();
+        assert!(state.room.hierarchy.0.contains(&ada.peer));
+        assert_eq!(state.room.term, 1);
+    }
+
+    #[test]
+    fn moderation_applies_to_the_peer_named() {
+        let mut h = Harness::new();
+        h.handle(Event::StartRoom(RoomOptions::default()));
+        let (bob, carol, dave) = (Other::new("bob"), Other::new("carol"), Other::new("dave"));
+        let bob_id = h.join(&bob);
+        let carol_id = h.join(&carol);
+        h.join(&dave);
+
+        h.handle(Event::Moderate(Sanction::Mute, "bob".into()));
+        assert!(h.admin().moderation.is_muted(bob.peer.id()));
+        let text = ForwardPayload::Text("hi".into());
+        let muted = Envelope::signed_by(&bob.identity, bob.peer.clone(), text.clone());
+        let heard = Envelope::signed_by(&carol.identity, carol.peer.clone(), text);
+        h.send(bob_id, Payload::Forward(muted.clone()));
+        h.send(carol_id, Payload::Forward(heard.clone()));
+        let ids = h.manager.history.ids();
+        assert!(!ids.contains(&muted.id) && ids.contains(&heard.id));
+
+        h.handle(Event::Moderate(Sanction::Kick, "carol".into()));
+        assert!(!h.admin().peers.contains_key(&carol_id));
+        assert!(!h.admin().room.hierarchy.0.contains(&carol.peer));
+
+        h.handle(Event::Moderate(Sanction::Ban, "dave".into()));
+        assert!(!h.admin().room.hierarchy.0.contains(&dave.peer));
+        let con_id = h.open();
+        h.send(
+            con_id,
+            Payload::JoinReq(dave.peer.clone(), Capabilities::local()),
+        );
+        assert!(!h.admin().identifying.contains_key(&con_id));
+        assert!(!h.admin().room.hierarchy.0.contains(&dave.peer));
+    }
 }
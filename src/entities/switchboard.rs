@@ -0,0 +1,136 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+};
+
+use crossbeam_channel::Sender;
+use ws::{Builder, Factory, Sender as WsSender, Settings, WebSocket};
+
+use crate::tls::Tls;
+
+use super::{next_connection_id, Event, Handler, Heartbeat};
+
+/// A websocket server whose port is shared by the rooms attached to it.
+///
+/// Each room gets a slot, and connections pick theirs by the path they request. Connections
+/// for slot 0 may leave the path out, so peers dialing a bare address reach the first room.
+#[derive(Debug, Clone)]
+pub struct Switchboard {
+    port: u16,
+    routes: Routes,
+}
+
+/// Where to deliver the events of each slot's connections
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Routes(Arc<Mutex<Vec<Sender<Event>>>>);
+
+impl Routes {
+    /// Where the events of a connection requesting `path` go, if it names an attached slot
+    pub(crate) fn route(&self, path: &str) -> Option<Sender<Event>> {
+        let slot = match path.trim_start_matches('/') {
+            "" => 0,
+            slot => slot.parse::<usize>().ok()?,
+        };
+        self.0.lock().unwrap().get(slot).cloned()
+    }
+}
+
+impl Switchboard {
+    /// Accept connections on `port`, or on one the system picks if it's 0, from threads of
+    /// their own. They're taken on every address unless `bind_addr` limits them to one.
+    ///
+    /// Attached rooms get [`Event::Error`] if the server stops, as nobody can join or elect
+    /// them then.
+    pub fn listen(
+        bind_addr: Option<IpAddr>,
+        port: u16,
+        heartbeat: Heartbeat,
+        tls: Option<Arc<Tls>>,
+    ) -> io::Result<Self> {
+        let settings = Settings {
+            encrypt_server: tls.is_some(),
+            ..Settings::default()
+        };
+        let routes = Routes::default();
+
+        // Unless the system keeps IPv6 sockets to IPv6, the first one takes IPv4 connections
+        // as well, and binding the second one fails
+        let addrs = match bind_addr {
+            Some(addr) => vec![addr],
+            None => vec![
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            ],
+        };
+        let mut port = port;
+        let mut servers = Vec::new();
+        let mut failure = None;
+        for addr in addrs {
+            let routes = routes.clone();
+            let tls = tls.clone();
+            let bound = bind(addr, port, settings, move |out: WsSender| {
+                log::info!("New connection");
+                Handler::incoming(routes.clone(), next_connection_id(), out, heartbeat)
+                    .with_tls(tls.clone())
+            });
+            match bound {
+                Ok(server) => {
+                    // Both servers take the port the system picked for the first
+                    port = server.local_addr()?.port();
+                    servers.push((addr, server));
+                }
+                Err(e) => {
+                    log::info!("Not listening on {addr}: {e}");
+                    failure = Some(e);
+                }
+            }
+        }
+        if servers.is_empty() {
+            return Err(failure.unwrap_or_else(|| io::Error::from(io::ErrorKind::AddrNotAvailable)));
+        }
+
+        for (addr, server) in servers {
+            let routes = routes.clone();
+            std::thread::Builder::new()
+                .name("websocket server".into())
+                .spawn(move || {
+                    log::info!("Websocket server started on {addr} port {port}");
+                    if let Err(e) = server.run() {
+                        log::error!("Websocket server on {addr} failed: {e}");
+                        for events_tx in routes.0.lock().unwrap().iter() {
+                            let error = format!("Stopped accepting connections: {e}");
+                            let _ = events_tx.send(Event::Error(error));
+                        }
+                    }
+                })?;
+        }
+        Ok(Switchboard { port, routes })
+    }
+
+    /// Port the server listens on
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Deliver the events of connections for a new slot to `events_tx`, returning the slot
+    pub(crate) fn attach(&self, events_tx: Sender<Event>) -> u16 {
+        let mut routes = self.routes.0.lock().unwrap();
+        routes.push(events_tx);
+        (routes.len() - 1) as u16
+    }
+}
+
+/// A server taking connections on `port` of `addr` to handlers made by `factory`
+fn bind<F: Factory>(
+    addr: IpAddr,
+    port: u16,
+    settings: Settings,
+    factory: F,
+) -> io::Result<WebSocket<F>> {
+    let server = Builder::new()
+        .with_settings(settings)
+        .build(factory)
+        .map_err(io::Error::other)?;
+    server.bind((addr, port)).map_err(io::Error::other)
+}
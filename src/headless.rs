@@ -0,0 +1,442 @@
+//! Running without the terminal UI, for an always-on admin holding a room, a bot posting to
+//! one, or a script that's done after a single command.
+//!
+//! Lines read from stdin are sent to the room, or run as commands when they start with `/`,
+//! and the room's timeline is printed to stdout as it grows.
+
+use std::{
+    collections::VecDeque,
+    io::{self, BufRead, Write},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use color_eyre::{eyre::eyre, Result};
+use crossbeam_channel::{never, select, unbounded, Receiver, Sender};
+
+use vlawn::{
+    identity::Identity, tls::Tls, Endpoint, Event, ForwardPayload, Heartbeat, RoomOptions,
+    StateManager, StoredRoom, Update, WS_PORT,
+};
+
+use crate::{
+    cli::Start,
+    config::Config,
+    ui::command::{self, Command},
+};
+
+/// How long to look for a room by name, which announces itself every second
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a message we send waits for the group key, before we give up on sending it
+const KEY_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long to wait for the admin to relay a message we sent back to us
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often to check on timeouts while no events come in
+const TICK: Duration = Duration::from_millis(100);
+
+/// Room we're looking for by name, to join once it's discovered
+struct Search {
+    name: String,
+    /// Why the name isn't a host, if it was given as either
+    unresolved: Option<String>,
+    deadline: Instant,
+}
+
+/// Join the room we're looking for once it's discovered, failing if it isn't in time
+fn look_for(
+    search: &mut Option<Search>,
+    manager: &StateManager,
+    events_tx: &Sender<Event>,
+) -> Result<()> {
+    let Some(room) = search else {
+        return Ok(());
+    };
+    if let Some(endpoint) = manager.discovered_room(&room.name) {
+        events_tx.send(Event::JoinSend(endpoint))?;
+        *search = None;
+    } else if Instant::now() >= room.deadline {
+        let found = format!("Found no room called {} on the LAN", room.name);
+        return Err(match &room.unresolved {
+            Some(e) => eyre!("{found}, and it's not a host we could resolve: {e}"),
+            None => eyre!(found),
+        });
+    }
+    Ok(())
+}
+
+/// Head for `start`, returning the search for a room if it has to be found first.
+///
+/// A room we create is protected by `passphrase` unless it's already given one.
+fn head_for(
+    start: Start,
+    events_tx: &Sender<Event>,
+    passphrase: Option<String>,
+) -> Result<Option<Search>> {
+    let options = match start {
+        Start::Lobby => RoomOptions::default(),
+        Start::Host(options) => options,
+        Start::Join { endpoint, .. } => {
+            events_tx.send(Event::JoinSend(endpoint))?;
+            return Ok(None);
+        }
+        Start::Find { name, unresolved } => {
+            events_tx.send(Event::Discover)?;
+            return Ok(Some(Search {
+                name,
+                unresolved,
+                deadline: Instant::now() + SEARCH_TIMEOUT,
+            }));
+        }
+    };
+    events_tx.send(Event::StartRoom(RoomOptions {
+        passphrase: options.passphrase.or(passphrase),
+        ..options
+    }))?;
+    Ok(None)
+}
+
+/// Fail if the room we tried to get into turned us away or couldn't be reached, as there's no
+/// lobby to fall back to
+fn check_entry(manager: &mut StateManager) -> Result<()> {
+    if manager.peers().is_none() {
+        if let Some(reason) = manager.take_rejection() {
+            return Err(eyre!("Could not join: {reason}"));
+        }
+        if let Some(error) = manager.take_error() {
+            return Err(eyre!(error));
+        }
+    }
+    Ok(())
+}
+
+/// Go where `start` says, holding a room of our own without anywhere else to go, until told
+/// to `/quit`.
+///
+/// A room we create is protected by `passphrase` if given, and it answers the challenge of a
+/// protected room we join. Without it, the first line read while challenged is the answer.
+pub fn run(
+    tls: Option<Arc<Tls>>,
+    config: &Config,
+    heartbeat: Heartbeat,
+    start: Start,
+    passphrase: Option<String>,
+) -> Result<()> {
+    let (events_tx, events_rx) = unbounded::<Event>();
+    let mut manager = StateManager::new(events_tx.clone(), heartbeat, tls);
+    let updates = manager.subscribe();
+    manager.listen(config.bind, config.port.unwrap_or(WS_PORT));
+    if let Some(name) = &config.name {
+        events_tx.send(Event::Rename(name.clone()))?;
+    }
+    let mut search = head_for(start, &events_tx, passphrase.clone())?;
+
+    let mut lines = read_lines()?;
+    let mut pending = VecDeque::new();
+    let mut stdout = io::stdout().lock();
+
+    loop {
+        select! {
+            recv(events_rx) -> event => {
+                let event = event?;
+                log::info!("Received event: {event:?}");
+                manager.handle(event);
+            }
+            recv(lines) -> line => match line {
+                Ok(line) => pending.push_back(line),
+                // Keep holding the room once input runs out, as when started with stdin closed
+                Err(_) => lines = never(),
+            },
+            default(TICK) => {}
+        }
+
+        look_for(&mut search, &manager, &events_tx)?;
+        check_entry(&mut manager)?;
+
+        if manager.awaiting_secret() {
+            let secret = passphrase.clone().or_else(|| pending.pop_front());
+            if let Some(secret) = secret {
+                events_tx.send(Event::Secret(secret))?;
+            }
+        } else if manager.in_room() {
+            // Lines read before we got in wait until then, so `echo hi | vlawn` says hi
+            while let Some(line) = pending.pop_front() {
+                if let Some(event) = submit(&mut stdout, &line)? {
+                    events_tx.send(event)?;
+                }
+            }
+        }
+
+        for update in updates.try_iter() {
+            writeln!(stdout, "{}", render(update))?;
+        }
+        stdout.flush()?;
+
+        if manager.has_left() {
+            return Ok(());
+        }
+    }
+}
+
+/// Forward the lines of stdin from a thread of their own, until it's closed
+fn read_lines() -> io::Result<Receiver<String>> {
+    let (lines_tx, lines_rx) = unbounded();
+    std::thread::Builder::new()
+        .name("stdin".into())
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::error!("Failed to read stdin: {e}");
+                        return;
+                    }
+                };
+                if !line.trim().is_empty() && lines_tx.send(line).is_err() {
+                    return;
+                }
+            }
+        })?;
+    Ok(lines_rx)
+}
+
+/// Print the rooms announcing themselves on the LAN within `wait`
+pub fn list(wait: Duration, heartbeat: Heartbeat) -> Result<()> {
+    let (events_tx, events_rx) = unbounded::<Event>();
+    let mut manager = StateManager::new(events_tx.clone(), heartbeat, None);
+    events_tx.send(Event::Discover)?;
+
+    let deadline = Instant::now() + wait;
+    while let Ok(event) = events_rx.recv_deadline(deadline) {
+        log::info!("Received event: {event:?}");
+        manager.handle(event);
+        if let Some(error) = manager.take_error() {
+            return Err(eyre!(error));
+        }
+    }
+
+    let rooms = manager.discovered().cloned().unwrap_or_default();
+    if rooms.is_empty() {
+        eprintln!("No rooms found on the LAN");
+    }
+    let mut stdout = io::stdout().lock();
+    for room in rooms {
+        let latency = room
+            .latency
+            .map(|l| format!("{}ms", l.as_millis()))
+            .unwrap_or_else(|| "?".into());
+        writeln!(
+            stdout,
+            "{:<24} {:<16} {:>3} {:>6}  {}",
+            room.beacon.name,
+            room.beacon.admin.username(),
+            room.beacon.members,
+            latency,
+            room.endpoint()
+        )?;
+    }
+    Ok(())
+}
+
+/// Join the room `start` leads to, send `message` once we hold its group key, and leave after
+/// the admin relayed it back to us.
+///
+/// We join under a throwaway identity, as a session of ours may be in the room already and
+/// leaving as it would drop it from the room. A protected room is answered with `passphrase`,
+/// as there's no one to ask for it.
+pub fn send(
+    tls: Option<Arc<Tls>>,
+    config: &Config,
+    heartbeat: Heartbeat,
+    start: Start,
+    message: String,
+    passphrase: Option<String>,
+) -> Result<()> {
+    Identity::use_temporary()?;
+    let (events_tx, events_rx) = unbounded::<Event>();
+    let mut manager = StateManager::new(events_tx.clone(), heartbeat, tls);
+    let updates = manager.subscribe();
+    // A port of our own, as the one configured may be taken by a session we run alongside
+    manager.listen(config.bind, 0);
+    if let Some(name) = &config.name {
+        events_tx.send(Event::Rename(name.clone()))?;
+    }
+    let mut search = head_for(start, &events_tx, None)?;
+
+    let mut message = Some(message);
+    let mut joined_at = None;
+    let mut sent_at = None;
+    loop {
+        match events_rx.recv_timeout(TICK) {
+            Ok(event) => {
+                log::info!("Received event: {event:?}");
+                manager.handle(event);
+            }
+            Err(e) if e.is_timeout() => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        look_for(&mut search, &manager, &events_tx)?;
+        check_entry(&mut manager)?;
+
+        if manager.awaiting_secret() {
+            let secret = passphrase.clone().ok_or_else(|| {
+                eyre!("The room is protected, pass --passphrase with its passphrase or an invite code")
+            })?;
+            events_tx.send(Event::Secret(secret))?;
+        }
+
+        if manager.in_room() && message.is_some() {
+            let joined_at = *joined_at.get_or_insert_with(Instant::now);
+            if let Some(text) = message.take_if(|_| manager.holds_group_key()) {
+                events_tx.send(Event::SubmitMessage(ForwardPayload::Text(text)))?;
+                sent_at = Some(Instant::now());
+            } else if joined_at.elapsed() >= KEY_TIMEOUT {
+                return Err(eyre!(
+                    "The room didn't hand us its key in time, so the message can't be sealed"
+                ));
+            }
+        }
+
+        for update in updates.try_iter() {
+            match update {
+                Update::Message(envelope, _)
+                    if sent_at.is_some() && envelope.sender.id() == manager.peer().id() =>
+                {
+                    sent_at = None;
+                    events_tx.send(Event::Leave)?;
+                }
+                Update::Notice(notice) if notice.is_error && sent_at.is_some() => {
+                    return Err(eyre!(notice.text));
+                }
+                _ => {}
+            }
+        }
+        if sent_at.is_some_and(|at| at.elapsed() >= DELIVERY_TIMEOUT) {
+            return Err(eyre!("The room didn't relay the message in time"));
+        }
+
+        if manager.has_left() {
+            return Ok(());
+        }
+    }
+}
+
+/// Print the messages stored of the room called `room`, or with `room` as its id
+pub fn history(room: &str) -> Result<()> {
+    let rooms = StoredRoom::all()?;
+    let matching: Vec<&StoredRoom> = rooms
+        .iter()
+        .filter(|r| r.name.as_deref() == Some(room) || format!("{:016x}", r.id) == room)
+        .collect();
+    let stored = match matching.as_slice() {
+        [stored] => *stored,
+        [] => {
+            let known: Vec<String> = rooms.iter().map(describe).collect();
+            return Err(match known.is_empty() {
+                true => eyre!("No messages of any room are stored"),
+                false => eyre!(
+                    "No messages of {room} are stored, only of {}",
+                    known.join(", ")
+                ),
+            });
+        }
+        several => {
+            let ids: Vec<String> = several.iter().map(|r| format!("{:016x}", r.id)).collect();
+            return Err(eyre!(
+                "Several rooms called {room} are stored, pick one by id: {}",
+                ids.join(", ")
+            ));
+        }
+    };
+
+    let mut stdout = io::stdout().lock();
+    for update in stored.load()? {
+        writeln!(stdout, "{}", render(update))?;
+    }
+    Ok(())
+}
+
+/// A stored room as listed in errors, by name if it has one
+fn describe(room: &StoredRoom) -> String {
+    match &room.name {
+        Some(name) => name.clone(),
+        None => format!("{:016x}", room.id),
+    }
+}
+
+fn resolve(host: &str) -> Result<Endpoint> {
+    Endpoint::resolve(host).map_err(|e| eyre!("Could not resolve {host}: {e}"))
+}
+
+/// What to do about a line of input, printing help and errors ourselves
+fn submit(out: &mut impl Write, line: &str) -> Result<Option<Event>> {
+    let Some(line) = line.trim().strip_prefix('/') else {
+        return Ok(Some(Event::SubmitMessage(ForwardPayload::Text(
+            line.to_string(),
+        ))));
+    };
+    let event = match command::parse(line) {
+        Ok(Command::Help(None)) => {
+            for spec in command::COMMANDS {
+                writeln!(out, "{:<16} {}", spec.usage(), spec.help)?;
+            }
+            return Ok(None);
+        }
+        Ok(Command::Help(Some(spec))) => {
+            writeln!(out, "{}: {}", spec.usage(), spec.help)?;
+            return Ok(None);
+        }
+        Ok(Command::Join(host)) => match resolve(&host) {
+            Ok(endpoint) => Event::JoinSend(endpoint),
+            Err(e) => {
+                writeln!(out, "! {e}")?;
+                return Ok(None);
+            }
+        },
+        Ok(Command::Nick(username)) => Event::Rename(username),
+        Ok(Command::Me(action)) => Event::SubmitMessage(ForwardPayload::Action(action)),
+        Ok(Command::Topic(topic)) => Event::SetTopic(topic),
+        // There's no lobby to leave for
+        Ok(Command::Leave | Command::Quit) => Event::Leave,
+        Ok(Command::Invite) => Event::CreateInvite,
+        Ok(Command::Moderate(sanction, target)) => Event::Moderate(sanction, target),
+        Err(e) => {
+            writeln!(out, "! {e}")?;
+            return Ok(None);
+        }
+    };
+    Ok(Some(event))
+}
+
+/// A line of the timeline as printed
+fn render(update: Update) -> String {
+    match update {
+        Update::Notice(notice) => {
+            let mark = if notice.is_error { '!' } else { '—' };
+            format!("{} {mark} {}", timestamp(notice.at), notice.text)
+        }
+        Update::Message(envelope, payload) => {
+            let at = timestamp(envelope.sent_at);
+            let sender = envelope.sender.username();
+            match payload {
+                Some(ForwardPayload::Text(text)) => format!("{at} {sender}: {text}"),
+                Some(ForwardPayload::Notification(text)) => format!("{at} — {text}"),
+                Some(ForwardPayload::Action(text)) => format!("{at} * {sender} {text}"),
+                Some(ForwardPayload::Sealed(_)) | None => {
+                    format!("{at} {sender}: <encrypted message>")
+                }
+            }
+        }
+    }
+}
+
+/// Local date and time of a timestamp in milliseconds since the unix epoch
+fn timestamp(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
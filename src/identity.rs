@@ -0,0 +1,191 @@
+//! The key pair identifying this installation to other peers.
+//!
+//! Its public half is our [`PeerId`], and the messages we write are signed with it, so nobody
+//! can pass off as us by picking the same username or sitting behind the same address. Admins
+//! also have joiners sign a fresh nonce before taking the id they claim at its word, along with
+//! the room and admin asking, so the proof can't be passed on to another room. Admins in turn
+//! vouch for the TLS certificate they present, so it can be pinned to their id.
+
+use std::{fs, io, sync::OnceLock};
+
+use ed25519_dalek::{Signature, Signer, SigningKey};
+
+use crate::{access::Nonce, entities::PeerId};
+
+static LOCAL: OnceLock<Identity> = OnceLock::new();
+
+/// Leads what's signed to prove an identity, so the proof can't pass for a signed message
+const PROOF_CONTEXT: &[u8] = b"vlawn identity proof";
+/// Leads what an admin signs to vouch for its certificate
+const CERTIFICATE_CONTEXT: &[u8] = b"vlawn certificate";
+
+pub struct Identity {
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// Our identity, loaded from the data directory or created there on first use
+    pub fn local() -> &'static Identity {
+        LOCAL.get_or_init(|| {
+            Self::load().unwrap_or_else(|e| {
+                log::error!("Failed to load identity, using a temporary one: {e}");
+                Self::generate()
+            })
+        })
+    }
+
+    /// Go by a throwaway identity in this process rather than the stored one, so a one-off
+    /// command run alongside a session isn't taken for that session by the room.
+    ///
+    /// Fails if our identity was already used.
+    pub fn use_temporary() -> io::Result<()> {
+        LOCAL
+            .set(Self::generate())
+            .map_err(|_| io::Error::other("our identity is already in use"))
+    }
+
+    pub(crate) fn generate() -> Self {
+        Identity {
+            signing: SigningKey::from_bytes(&rand::random()),
+        }
+    }
+
+    fn load() -> io::Result<Self> {
+        let path = crate::paths::data_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+            .join("identity");
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let secret: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad identity file"))?;
+                Ok(Identity {
+                    signing: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                fs::create_dir_all(path.parent().unwrap())?;
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, identity.signing.to_bytes())?;
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))?;
+                }
+                fs::rename(tmp, path)?;
+                log::info!("Created identity {}", identity.id());
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn id(&self) -> PeerId {
+        PeerId(self.signing.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing.sign(message)
+    }
+
+    /// Prove to `admin`, which sent `nonce` for the room `room_id`, that we hold the key of
+    /// our id
+    pub fn prove(&self, nonce: &Nonce, room_id: u64, admin: &PeerId) -> Signature {
+        self.sign(&proof_bytes(nonce, room_id, admin))
+    }
+
+    /// Vouch for the certificate with `fingerprint` as ours, to whoever we sent `nonce` to
+    /// prove its identity for the room `room_id`. Without TLS, the fingerprint is empty.
+    pub fn certify(&self, nonce: &Nonce, room_id: u64, fingerprint: &str) -> Signature {
+        self.sign(&certificate_bytes(nonce, room_id, fingerprint))
+    }
+}
+
+/// Whether `proof` shows that whoever answered `nonce`, which `admin` sent for the room
+/// `room_id`, holds the key of `id`
+pub fn verify(id: &PeerId, nonce: &Nonce, room_id: u64, admin: &PeerId, proof: &Signature) -> bool {
+    id.verify(&proof_bytes(nonce, room_id, admin), proof)
+}
+
+/// Whether `admin` vouched for the certificate with `fingerprint` with `signature`, when
+/// asking us to sign `nonce` for the room `room_id`
+pub fn verify_certificate(
+    admin: &PeerId,
+    nonce: &Nonce,
+    room_id: u64,
+    fingerprint: &str,
+    signature: &Signature,
+) -> bool {
+    admin.verify(&certificate_bytes(nonce, room_id, fingerprint), signature)
+}
+
+fn certificate_bytes(nonce: &Nonce, room_id: u64, fingerprint: &str) -> Vec<u8> {
+    [
+        CERTIFICATE_CONTEXT,
+        &room_id.to_be_bytes(),
+        nonce,
+        fingerprint.as_bytes(),
+    ]
+    .concat()
+}
+
+fn proof_bytes(nonce: &Nonce, room_id: u64, admin: &PeerId) -> Vec<u8> {
+    [PROOF_CONTEXT, &room_id.to_be_bytes(), &admin.0, nonce].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_only_hold_for_the_room_and_admin_asking() {
+        let (joiner, admin, other) = (Identity::generate(), PeerId([1; 32]), PeerId([2; 32]));
+        let nonce = rand::random();
+        let proof = joiner.prove(&nonce, 7, &admin);
+
+        assert!(verify(&joiner.id(), &nonce, 7, &admin, &proof));
+        assert!(!verify(&joiner.id(), &nonce, 8, &admin, &proof));
+        assert!(!verify(&joiner.id(), &nonce, 7, &other, &proof));
+        assert!(!verify(&joiner.id(), &rand::random(), 7, &admin, &proof));
+        assert!(!verify(&other, &nonce, 7, &admin, &proof));
+    }
+
+    #[test]
+    fn admins_only_vouch_for_their_own_certificate() {
+        let admin = Identity::generate();
+        let nonce = rand::random();
+        let signature = admin.certify(&nonce, 7, "ab12");
+
+        assert!(verify_certificate(
+            &admin.id(),
+            &nonce,
+            7,
+            "ab12",
+            &signature
+        ));
+        assert!(!verify_certificate(
+            &admin.id(),
+            &nonce,
+            7,
+            "cd34",
+            &signature
+        ));
+        assert!(!verify_certificate(&admin.id(), &nonce, 7, "", &signature));
+        assert!(!verify_certificate(
+            &admin.id(),
+            &nonce,
+            8,
+            "ab12",
+            &signature
+        ));
+        assert!(!verify_certificate(
+            &PeerId([1; 32]),
+            &nonce,
+            7,
+            "ab12",
+            &signature
+        ));
+    }
+}
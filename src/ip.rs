@@ -1,15 +1,121 @@
-use std::{net::Ipv4Addr, str::FromStr};
-
-pub fn get_local_ipv4() -> Ipv4Addr {
-    let addr = String::from_iter(
-        std::process::Command::new("hostname")
-            .args(["-I"])
-            .output()
-            .unwrap()
-            .stdout
-            .iter()
-            .map(|t| *t as char),
-    )
-    .replace(" \n", "");
-    Ipv4Addr::from_str(&addr).unwrap()
+//! Which of our addresses other peers should reach us at.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::OnceLock,
+};
+
+/// Address or interface name picked by the user, which wins over whatever we'd pick
+static PREFERRED: OnceLock<String> = OnceLock::new();
+
+/// An address of one of our network interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub interface: String,
+    /// Index of the interface, which scopes its link-local IPv6 addresses
+    pub index: Option<u32>,
+    pub addr: IpAddr,
+}
+
+impl Candidate {
+    /// Link-local addresses only reach peers on the same link, so others are preferred
+    fn is_link_local(&self) -> bool {
+        is_link_local(&self.addr)
+    }
+}
+
+/// Whether `addr` only reaches the link it's on, which for IPv6 needs the interface as well
+pub fn is_link_local(addr: &IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(addr) => addr.is_link_local(),
+        IpAddr::V6(addr) => addr.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
+/// Use `choice`, an address or the name of an interface, instead of picking one ourselves.
+///
+/// Only the first call has any effect, as peers we already told our address keep using it.
+pub fn prefer(choice: String) {
+    if PREFERRED.set(choice).is_err() {
+        log::warn!("Ignoring another preferred address, one was already set");
+    }
+}
+
+/// Addresses of our interfaces other peers could reach us at, leaving out loopback
+pub fn candidates() -> io::Result<Vec<Candidate>> {
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|iface| !iface.is_loopback())
+        .map(|iface| Candidate {
+            addr: iface.ip(),
+            index: iface.index,
+            interface: iface.name,
+        })
+        .collect())
+}
+
+/// Indices of the interfaces with an IPv6 address, which rooms are announced on
+pub fn ipv6_interfaces() -> Vec<u32> {
+    let mut indices: Vec<u32> = candidates()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| c.addr.is_ipv6())
+        .filter_map(|c| c.index)
+        .collect();
+    indices.sort_unstable();
+    indices.dedup();
+    indices
+}
+
+/// Our address that packets to `target` leave from, as the routing table has it.
+///
+/// Connecting a UDP socket picks a route without sending anything.
+pub fn route_to(target: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified = match target {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))?;
+    socket.connect(target)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// The address other peers should reach us at: the one the user preferred, else the one
+/// routing to `target` if we're about to reach it, else the first one that isn't link-local.
+pub fn local_addr(target: Option<SocketAddr>) -> IpAddr {
+    let candidates = candidates().unwrap_or_else(|e| {
+        log::error!("Failed to list network interfaces: {e}");
+        Vec::new()
+    });
+
+    if let Some(choice) = PREFERRED.get() {
+        if let Ok(addr) = choice.parse() {
+            return addr;
+        }
+        let on_interface = candidates.iter().filter(|c| c.interface == *choice);
+        match on_interface.min_by_key(|c| (c.is_link_local(), c.addr.is_ipv6())) {
+            Some(candidate) => return candidate.addr,
+            None => log::warn!("No address on interface {choice}, picking another one"),
+        }
+    }
+
+    if let Some(target) = target {
+        match route_to(target) {
+            Ok(addr) if !addr.is_unspecified() => return addr,
+            Ok(_) => log::warn!("No route to {target}"),
+            Err(e) => log::warn!("No route to {target}: {e}"),
+        }
+    }
+
+    match candidates
+        .iter()
+        .min_by_key(|c| (c.is_link_local(), c.addr.is_ipv6()))
+    {
+        Some(candidate) => candidate.addr,
+        None => {
+            log::error!("No network interface to reach other peers on, using loopback");
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        }
+    }
 }
@@ -0,0 +1,48 @@
+//! Chat rooms on the LAN, without a server.
+//!
+//! Every peer runs a [`StateManager`], which is driven by the [`Event`]s of the channel it was
+//! created with: those of its connections, and those submitted by whoever embeds it. One peer
+//! leads each [`Room`] as its admin and relays the [`Message`]s of the others, which elect a
+//! new admin should it go away.
+//!
+//! ```no_run
+//! use vlawn::{Event, ForwardPayload, Heartbeat, RoomOptions, StateManager, Update};
+//!
+//! let (events_tx, events_rx) = crossbeam_channel::unbounded();
+//! let mut manager = StateManager::new(events_tx.clone(), Heartbeat::default(), None);
+//! let updates = manager.subscribe();
+//! manager.listen(None, vlawn::WS_PORT);
+//!
+//! events_tx.send(Event::StartRoom(RoomOptions::default()))?;
+//! events_tx.send(Event::SubmitMessage(ForwardPayload::Text("hello".into())))?;
+//! for event in events_rx {
+//!     manager.handle(event);
+//!     for update in updates.try_iter() {
+//!         if let Update::Message(envelope, Some(ForwardPayload::Text(text))) = update {
+//!             println!("{}: {text}", envelope.sender.username());
+//!         }
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! A process can be in several rooms at once, with a manager for each. They either listen on
+//! ports of their own, or [`share`](StateManager::share) the port of a [`Switchboard`].
+
+mod access;
+mod admin;
+mod crypto;
+pub mod entities;
+pub mod identity;
+pub mod ip;
+mod member;
+pub mod paths;
+mod relay;
+mod store;
+pub mod tls;
+
+pub use entities::{
+    Endpoint, Envelope, Event, ForwardPayload, Heartbeat, Message, Payload, Peer, Room,
+    RoomOptions, StateManager, Switchboard, Update, VlawnError, WS_PORT,
+};
+pub use store::StoredRoom;
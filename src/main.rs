@@ -1,27 +1,74 @@
-mod admin;
-mod entities;
-mod ip;
-mod member;
+mod cli;
+mod config;
+mod headless;
 mod ui;
 
-use std::fs::File;
+use std::{fs::File, path::PathBuf, sync::Arc, time::Duration};
 
-use simplelog::{Config, WriteLogger};
+use clap::Parser;
+use simplelog::WriteLogger;
+use vlawn::tls;
 
-use color_eyre::Result;
+use color_eyre::{eyre::WrapErr, Result};
 
 fn main() -> Result<()> {
-    let now = chrono::Local::now();
-    WriteLogger::init(
-        log::LevelFilter::Info,
-        Config::default(),
-        File::create(format!("{}.log", now.format("%Y%m%y_%H%M%S"))).unwrap(),
-    )
-    .unwrap();
-
     color_eyre::install()?;
+    let args = cli::Args::parse();
+    // Reading stored messages needs neither a log nor the network
+    if let Some(cli::Command::History { room }) = &args.command {
+        return headless::history(room);
+    }
+    let mut config = config::Config::load(args.config.as_deref())?;
+    config.apply(&args);
+
+    let log_path = config.log.path.clone().unwrap_or_else(|| {
+        let now = chrono::Local::now();
+        PathBuf::from(format!("{}.log", now.format("%Y%m%y_%H%M%S")))
+    });
+    if let Some(dir) = log_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        std::fs::create_dir_all(dir)?;
+    }
+    let log_file = File::options()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .wrap_err(format!("Could not open log file {}", log_path.display()))?;
+    WriteLogger::init(config.log.level, simplelog::Config::default(), log_file)?;
+
+    if let Some(interface) = config.interface.clone() {
+        vlawn::ip::prefer(interface);
+    }
+
+    let heartbeat = config.heartbeat()?;
+    // Listing rooms goes nowhere, so there's no host or room to resolve
+    if let Some(cli::Command::List { wait }) = args.command {
+        return headless::list(Duration::from_secs(wait), heartbeat);
+    }
+
+    let tls = if config.tls {
+        Some(Arc::new(tls::Tls::load()?))
+    } else {
+        None
+    };
+
+    let passphrase = match &args.command {
+        Some(
+            cli::Command::Host { passphrase, .. }
+            | cli::Command::Join { passphrase, .. }
+            | cli::Command::Send { passphrase, .. },
+        ) => passphrase.clone(),
+        _ => std::env::var("VLAWN_PASSPHRASE").ok(),
+    };
+    let start = cli::Start::new(args.command.as_ref(), &config)?;
+    if let Some(cli::Command::Send { message, .. }) = args.command {
+        return headless::send(tls, &config, heartbeat, start, message, passphrase);
+    }
+    if config.headless {
+        return headless::run(tls, &config, heartbeat, start, passphrase);
+    }
+
     let terminal = ratatui::init();
-    let app_result = ui::App::new().run(terminal);
+    let app_result = ui::App::new(tls, &config, heartbeat, start, passphrase).run(terminal);
     ratatui::restore();
     app_result
 }
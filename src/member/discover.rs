@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use crossbeam_channel::{unbounded, Receiver, Sender, TryRecvError};
+use postcard::{from_bytes, to_allocvec};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::{
+    entities::{Datagram, Event, BEACON_GROUP, BEACON_GROUP_V6, BEACON_PORT},
+    ip,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Listens for room [`Beacon`](crate::entities::Beacon)s and forwards them as
+/// [`Event::Beacon`], probing each announcer's latency along the way.
+///
+/// The listening threads stop once every clone of the listener is dropped.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    _stop_tx: Sender<()>,
+}
+
+impl Listener {
+    /// Listen over IPv4, and over IPv6 on every interface that has it
+    pub fn spawn(events_tx: Sender<Event>) -> io::Result<Self> {
+        let (stop_tx, stop_rx) = unbounded::<()>();
+        let mut listening = false;
+        let mut failure = None;
+        for (family, socket) in [("IPv4", ipv4()), ("IPv6", ipv6())] {
+            match socket.and_then(|socket| listen(socket, events_tx.clone(), stop_rx.clone())) {
+                Ok(()) => listening = true,
+                Err(e) => {
+                    log::info!("Not listening for rooms over {family}: {e}");
+                    failure = Some(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) if !listening => Err(e),
+            _ => Ok(Self { _stop_tx: stop_tx }),
+        }
+    }
+}
+
+fn ipv4() -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, BEACON_PORT)).into())?;
+    socket.join_multicast_v4(&BEACON_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+/// Joins the link-local group on every interface with IPv6, as rooms are announced on each
+fn ipv6() -> io::Result<Socket> {
+    let interfaces = ip::ipv6_interfaces();
+    if interfaces.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no interface has an IPv6 address",
+        ));
+    }
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(
+        &SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, BEACON_PORT, 0, 0)).into(),
+    )?;
+    for index in interfaces {
+        if let Err(e) = socket.join_multicast_v6(&BEACON_GROUP_V6, index) {
+            log::warn!("Not listening for rooms on interface {index}: {e}");
+        }
+    }
+    Ok(socket)
+}
+
+/// Forward what arrives on `socket` from a thread of its own, until `stop_rx` disconnects
+fn listen(socket: Socket, events_tx: Sender<Event>, stop_rx: Receiver<()>) -> io::Result<()> {
+    let socket: UdpSocket = socket.into();
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    std::thread::Builder::new()
+        .name("beacon listener".into())
+        .spawn(move || {
+            let mut buf = [0u8; 1024];
+            let mut pings: HashMap<u64, Instant> = HashMap::new();
+            let mut next_nonce = 0u64;
+
+            while let Err(TryRecvError::Empty) = stop_rx.try_recv() {
+                let Ok((len, from)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+
+                let event = match from_bytes::<Datagram>(&buf[..len]) {
+                    Ok(Datagram::Beacon(beacon)) => {
+                        pings.retain(|_, sent| sent.elapsed() < Duration::from_secs(5));
+                        if let Ok(ping) = to_allocvec(&Datagram::Ping(next_nonce)) {
+                            if socket.send_to(&ping, from).is_ok() {
+                                pings.insert(next_nonce, Instant::now());
+                            }
+                        }
+                        next_nonce = next_nonce.wrapping_add(1);
+                        Event::Beacon(beacon, from)
+                    }
+                    Ok(Datagram::Pong(nonce)) => match pings.remove(&nonce) {
+                        Some(sent) => Event::Latency(from.ip(), sent.elapsed()),
+                        None => continue,
+                    },
+                    // Other listeners' probes are not for us
+                    Ok(Datagram::Ping(_)) => continue,
+                    Err(e) => {
+                        log::warn!("Ignoring malformed datagram from {from}: {e}");
+                        continue;
+                    }
+                };
+
+                if events_tx.send(event).is_err() {
+                    break;
+                }
+            }
+            log::info!("Beacon listener stopped");
+        })?;
+    Ok(())
+}
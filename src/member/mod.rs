@@ -1 +1 @@
-
+pub mod discover;
@@ -0,0 +1,125 @@
+//! Dialing IPv6 peers through a relay on loopback.
+//!
+//! `ws` resolves the host of the url it dials with `ToSocketAddrs`, which can't parse the
+//! bracketed IPv6 literals urls have. So we dial the relay over IPv4 loopback instead, which
+//! connects to the peer and copies bytes both ways. Any program on the host can dial loopback
+//! too, so the relay only takes a connection opened by this process. Only Linux tells who
+//! opened a connection, so elsewhere IPv6 peers can't be dialed.
+
+use std::{
+    io,
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    time::{Duration, Instant},
+};
+
+/// How long the relay waits for the connection it's for, and for the peer to answer
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Relay a single connection to `target`, returning the loopback address to dial instead
+pub fn spawn(target: SocketAddr) -> io::Result<SocketAddr> {
+    if cfg!(not(target_os = "linux")) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't relay to {target}, as this platform doesn't tell who dials the relay"),
+        ));
+    }
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.set_nonblocking(true)?;
+    let local = listener.local_addr()?;
+
+    std::thread::Builder::new()
+        .name("relay".into())
+        .spawn(move || {
+            let inbound = match accept(&listener) {
+                Ok(inbound) => inbound,
+                Err(e) => {
+                    log::warn!("Relay to {target} got no connection: {e}");
+                    return;
+                }
+            };
+            // Dropping the inbound connection tells whoever dialed us that the peer is out of
+            // reach
+            let outbound = match TcpStream::connect_timeout(&target, TIMEOUT) {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    log::warn!("Relay failed to reach {target}: {e}");
+                    return;
+                }
+            };
+            if let Err(e) = relay(inbound, outbound) {
+                log::warn!("Relay to {target} failed: {e}");
+            }
+        })?;
+    Ok(local)
+}
+
+fn accept(listener: &TcpListener) -> io::Result<TcpStream> {
+    let local = listener.local_addr()?;
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match listener.accept() {
+            Ok((stream, peer)) if opened_by_us(peer, local) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Ok((_, peer)) => {
+                log::warn!("Relay refused a connection from another program at {peer}")
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if Instant::now() >= deadline {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Whether the connection from `peer` to `local` was opened by this process, going by the
+/// socket at `peer` being among our open files
+#[cfg(target_os = "linux")]
+fn opened_by_us(peer: SocketAddr, local: SocketAddr) -> bool {
+    let Ok(table) = std::fs::read_to_string("/proc/net/tcp") else {
+        return false;
+    };
+    let port = |field: &str| {
+        let (_, port) = field.rsplit_once(':')?;
+        u16::from_str_radix(port, 16).ok()
+    };
+    // Lines are numbered, then list the local and remote address and, 7 fields on, the inode
+    let inode = table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let ours = port(fields.get(1)?)? == peer.port() && port(fields.get(2)?)? == local.port();
+        ours.then(|| fields.get(9).map(|inode| format!("socket:[{inode}]")))?
+    });
+    let (Some(socket), Ok(fds)) = (inode, std::fs::read_dir("/proc/self/fd")) else {
+        return false;
+    };
+    fds.flatten().any(|fd| {
+        std::fs::read_link(fd.path()).is_ok_and(|target| target.as_os_str() == socket.as_str())
+    })
+}
+
+/// Elsewhere there's no telling who opened a connection, so none is taken
+#[cfg(not(target_os = "linux"))]
+fn opened_by_us(_peer: SocketAddr, _local: SocketAddr) -> bool {
+    false
+}
+
+/// Copy between `inbound` and `outbound` until either side closes
+fn relay(inbound: TcpStream, outbound: TcpStream) -> io::Result<()> {
+    inbound.set_nodelay(true)?;
+    outbound.set_nodelay(true)?;
+    let (inbound_rx, outbound_tx) = (inbound.try_clone()?, outbound.try_clone()?);
+    std::thread::Builder::new()
+        .name("relay".into())
+        .spawn(move || pipe(inbound_rx, outbound_tx))?;
+    pipe(outbound, inbound);
+    Ok(())
+}
+
+fn pipe(mut from: TcpStream, mut to: TcpStream) {
+    let _ = io::copy(&mut from, &mut to);
+    let _ = to.shutdown(Shutdown::Write);
+}
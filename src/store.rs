@@ -0,0 +1,324 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use postcard::{from_bytes_cobs, to_allocvec_cobs};
+
+use crate::{
+    crypto::Keyring,
+    entities::{Envelope, Update},
+};
+
+/// Segments are rolled over once they grow past this size
+const SEGMENT_SIZE: u64 = 256 * 1024;
+/// Segments are compacted into one once there are more than this many
+const MAX_SEGMENTS: usize = 8;
+/// File next to the segments holding the room's name, so it can be found by it
+const NAME_FILE: &str = "name";
+
+/// Append-only log of the messages delivered in a room, kept across sessions.
+///
+/// Messages are stored as COBS framed postcard in numbered segment files under
+/// `<data dir>/rooms/<room id>/`.
+pub struct RoomStore {
+    room_id: u64,
+    dir: PathBuf,
+    segment: File,
+    segment_index: u32,
+    segment_len: u64,
+}
+
+impl RoomStore {
+    pub fn open(room_id: u64, name: &str) -> io::Result<Self> {
+        let dir = room_dir(room_id)?;
+        fs::write(dir.join(NAME_FILE), name)?;
+
+        if segments(&dir)?.len() > MAX_SEGMENTS {
+            compact(&dir)?;
+        }
+
+        let segment_index = segments(&dir)?.last().map_or(0, |(i, _)| *i);
+        let (segment, segment_len) = open_segment(&dir, segment_index)?;
+        Ok(RoomStore {
+            room_id,
+            dir,
+            segment,
+            segment_index,
+            segment_len,
+        })
+    }
+
+    pub fn room_id(&self) -> u64 {
+        self.room_id
+    }
+
+    /// Every message stored for the room, in the order they were appended
+    pub fn load(&self) -> io::Result<Vec<Envelope>> {
+        let mut envelopes = Vec::new();
+        for (_, path) in segments(&self.dir)? {
+            envelopes.extend(read_segment(&path)?);
+        }
+        Ok(envelopes)
+    }
+
+    pub fn append(&mut self, envelope: &Envelope) -> io::Result<()> {
+        if self.segment_len >= SEGMENT_SIZE {
+            self.segment_index += 1;
+            (self.segment, self.segment_len) = open_segment(&self.dir, self.segment_index)?;
+        }
+
+        let bytes = to_allocvec_cobs(envelope).map_err(io::Error::other)?;
+        self.segment.write_all(&bytes)?;
+        self.segment_len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+/// A room whose messages earlier sessions stored
+#[derive(Debug, Clone)]
+pub struct StoredRoom {
+    pub id: u64,
+    /// What the room was called, unless it was stored before names were
+    pub name: Option<String>,
+}
+
+impl StoredRoom {
+    /// Every room with stored messages, by id
+    pub fn all() -> io::Result<Vec<StoredRoom>> {
+        let Some(data_dir) = crate::paths::data_dir() else {
+            return Ok(Vec::new());
+        };
+        let entries = match fs::read_dir(data_dir.join("rooms")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut rooms = Vec::new();
+        for entry in entries {
+            let dir = entry?.path();
+            let id = dir
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| u64::from_str_radix(name, 16).ok());
+            let Some(id) = id else {
+                continue;
+            };
+            if segments(&dir)?.is_empty() {
+                continue;
+            }
+            let name = fs::read_to_string(dir.join(NAME_FILE)).ok();
+            rooms.push(StoredRoom { id, name });
+        }
+        rooms.sort_by_key(|room| room.id);
+        Ok(rooms)
+    }
+
+    /// Stored messages in order, opened with the group keys we were given in the room
+    pub fn load(&self) -> io::Result<Vec<Update>> {
+        let dir = room_dir(self.id)?;
+        let mut seen = HashSet::new();
+        let mut envelopes = Vec::new();
+        for (_, path) in segments(&dir)? {
+            let segment = read_segment(&path)?;
+            envelopes.extend(segment.into_iter().filter(|e| seen.insert(e.id)));
+        }
+        envelopes.sort_by_key(Envelope::order_key);
+
+        let mut keys = Keyring::new();
+        keys.attach(self.id);
+        Ok(envelopes
+            .into_iter()
+            .map(|envelope| {
+                let payload = keys.open(&envelope.payload);
+                Update::Message(Box::new(envelope), payload)
+            })
+            .collect())
+    }
+}
+
+/// Directory holding everything stored about the room `room_id`, created if missing
+pub fn room_dir(room_id: u64) -> io::Result<PathBuf> {
+    let dir = crate::paths::data_dir()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+        .join("rooms")
+        .join(format!("{room_id:016x}"));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Segment files in `dir`, ordered by index
+fn segments(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut segments: Vec<(u32, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            if path.extension()? != "seg" {
+                return None;
+            }
+            let index = path.file_stem()?.to_str()?.parse().ok()?;
+            Some((index, path))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, index: u32) -> PathBuf {
+    dir.join(format!("{index:08}.seg"))
+}
+
+fn open_segment(dir: &Path, index: u32) -> io::Result<(File, u64)> {
+    let segment = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(dir, index))?;
+    let len = segment.metadata()?.len();
+    Ok((segment, len))
+}
+
+fn read_segment(path: &Path) -> io::Result<Vec<Envelope>> {
+    let mut bytes = fs::read(path)?;
+    Ok(bytes
+        .split_inclusive_mut(|b| *b == 0)
+        // A frame without its terminator was cut short by a crash
+        .filter(|frame| frame.last() == Some(&0))
+        .filter_map(|frame| match from_bytes_cobs(frame) {
+            Ok(envelope) => Some(envelope),
+            Err(e) => {
+                log::warn!("Skipping corrupt message in {}: {e}", path.display());
+                None
+            }
+        })
+        .collect())
+}
+
+/// Rewrite every segment in `dir` into a single one without duplicates
+fn compact(dir: &Path) -> io::Result<()> {
+    let old = segments(dir)?;
+    let Some((last_index, _)) = old.last() else {
+        return Ok(());
+    };
+
+    let mut seen = HashSet::new();
+    let mut envelopes: Vec<Envelope> = Vec::new();
+    for (_, path) in &old {
+        for envelope in read_segment(path)? {
+            if seen.insert(envelope.id) {
+                envelopes.push(envelope);
+            }
+        }
+    }
+
+    let mut bytes = Vec::new();
+    for envelope in &envelopes {
+        bytes.extend(to_allocvec_cobs(envelope).map_err(io::Error::other)?);
+    }
+
+    // Write under the next index first, so a crash midway leaves at worst a duplicate
+    let compacted = segment_path(dir, last_index + 1);
+    let tmp = compacted.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, &compacted)?;
+    for (_, path) in old {
+        fs::remove_file(path)?;
+    }
+    log::info!(
+        "Compacted {} messages in {}",
+        envelopes.len(),
+        dir.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        entities::{ForwardPayload, Peer},
+        identity::Identity,
+    };
+
+    /// An empty directory of its own for each test
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vlawn-store-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn store(dir: &Path) -> RoomStore {
+        let (segment, segment_len) = open_segment(dir, 0).unwrap();
+        RoomStore {
+            room_id: 1,
+            dir: dir.to_path_buf(),
+            segment,
+            segment_index: 0,
+            segment_len,
+        }
+    }
+
+    fn envelope(text: &str) -> Envelope {
+        // Signing with the stored identity would create it in the data directory
+        let _ = Identity::use_temporary();
+        Envelope::new(Peer::get_local(), ForwardPayload::Text(text.to_string()))
+    }
+
+    #[test]
+    fn round_trips() {
+        let dir = temp_dir();
+        let mut store = store(&dir);
+        let envelopes = vec![envelope("one"), envelope("two"), envelope("three")];
+        for envelope in &envelopes {
+            store.append(envelope).unwrap();
+        }
+        assert_eq!(store.load().unwrap(), envelopes);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trips_across_segments() {
+        let dir = temp_dir();
+        let mut store = store(&dir);
+        let envelopes = vec![envelope("one"), envelope("two")];
+        store.append(&envelopes[0]).unwrap();
+        store.segment_len = SEGMENT_SIZE;
+        store.append(&envelopes[1]).unwrap();
+        assert_eq!(segments(&dir).unwrap().len(), 2);
+        assert_eq!(store.load().unwrap(), envelopes);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn round_trips_after_compaction() {
+        let dir = temp_dir();
+        let mut store = store(&dir);
+        let envelopes = vec![envelope("one"), envelope("two"), envelope("three")];
+        for envelope in &envelopes {
+            store.append(envelope).unwrap();
+            store.segment_len = SEGMENT_SIZE;
+        }
+        // Stored again after a reconnect, which compaction drops
+        store.append(&envelopes[1]).unwrap();
+
+        compact(&dir).unwrap();
+        let compacted = segments(&dir).unwrap();
+        assert_eq!(compacted.len(), 1);
+        assert_eq!(compacted[0].0, 4);
+        assert_eq!(read_segment(&compacted[0].1).unwrap(), envelopes);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn skips_a_message_cut_short() {
+        let dir = temp_dir();
+        let mut store = store(&dir);
+        let stored = envelope("stored");
+        store.append(&stored).unwrap();
+        let cut = to_allocvec_cobs(&envelope("cut")).unwrap();
+        store.segment.write_all(&cut[..cut.len() / 2]).unwrap();
+        assert_eq!(store.load().unwrap(), vec![stored]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Optional TLS transport for the links between peers.
+//!
+//! Every peer serves a self-signed certificate generated on first use. There's no authority
+//! vouching for those, so the certificate an admin presents the first time we connect to it is
+//! pinned to its [`PeerId`], and connecting is refused if it later presents another one. An
+//! admin signs the fingerprint of its certificate in the first message it sends, so the
+//! presented certificate is checked then rather than during the handshake, before anything
+//! else is said.
+
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    ssl::{SslAcceptor, SslConnector, SslMethod, SslStream, SslVerifyMode},
+    x509::{X509NameBuilder, X509Ref, X509},
+};
+use ws::util::TcpStream;
+
+use crate::entities::PeerId;
+
+/// Certificates we generate are valid for this many days
+const CERT_DAYS: u32 = 10 * 365;
+
+/// Our certificate, and the certificates of admins we've connected to before
+pub struct Tls {
+    acceptor: SslAcceptor,
+    connector: SslConnector,
+    fingerprint: String,
+    pins: Mutex<Pins>,
+    /// Fingerprints of the certificates presented on the connections we dialed, until they're
+    /// checked
+    presented: Arc<Mutex<HashMap<u32, String>>>,
+}
+
+impl Tls {
+    /// Load our certificate, generating one on first use
+    pub fn load() -> io::Result<Self> {
+        let dir = crate::paths::data_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory"))?
+            .join("tls");
+        fs::create_dir_all(&dir)?;
+
+        let (cert, key) = match load_identity(&dir) {
+            Ok(identity) => identity,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::info!("Generating TLS certificate");
+                let identity = generate_identity().map_err(io::Error::other)?;
+                save_identity(&dir, &identity)?;
+                identity
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).map_err(io::Error::other)?;
+        acceptor.set_certificate(&cert).map_err(io::Error::other)?;
+        acceptor.set_private_key(&key).map_err(io::Error::other)?;
+        acceptor.check_private_key().map_err(io::Error::other)?;
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(io::Error::other)?
+            .build();
+
+        let fingerprint = fingerprint(&cert).map_err(io::Error::other)?;
+        log::info!("TLS certificate fingerprint is {fingerprint}");
+        Ok(Tls {
+            acceptor: acceptor.build(),
+            connector,
+            fingerprint,
+            pins: Mutex::new(Pins::load()),
+            presented: Arc::default(),
+        })
+    }
+
+    /// SHA-256 fingerprint of our certificate, for comparing out of band
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Encrypt a connection a peer made to our server
+    pub fn accept(&self, sock: TcpStream) -> io::Result<SslStream<TcpStream>> {
+        self.acceptor.accept(sock).map_err(io::Error::other)
+    }
+
+    /// Encrypt connection `connection_id`, which we made, noting the certificate presented
+    /// for [`Tls::presented`]
+    pub fn connect(&self, sock: TcpStream, connection_id: u32) -> io::Result<SslStream<TcpStream>> {
+        let mut config = self.connector.configure().map_err(io::Error::other)?;
+        // Self-signed certificates carry no useful names, so they're trusted by pin alone
+        config.set_use_server_name_indication(false);
+        config.set_verify_hostname(false);
+
+        let presented = self.presented.clone();
+        config.set_verify_callback(SslVerifyMode::PEER, move |_, ctx| {
+            if ctx.error_depth() != 0 {
+                return true;
+            }
+            let Some(fingerprint) = ctx.current_cert().and_then(|c| fingerprint(c).ok()) else {
+                return false;
+            };
+            presented.lock().unwrap().insert(connection_id, fingerprint);
+            true
+        });
+        config.connect("", sock).map_err(io::Error::other)
+    }
+
+    /// Fingerprint of the certificate presented on connection `connection_id`, which we made
+    pub fn presented(&self, connection_id: u32) -> Option<String> {
+        self.presented.lock().unwrap().get(&connection_id).cloned()
+    }
+
+    /// Trust `fingerprint` for `admin` if it's the one pinned for it, or pin it if we had
+    /// nothing.
+    ///
+    /// Returns the pinned fingerprint if it's another.
+    pub fn pin(&self, admin: &PeerId, fingerprint: &str) -> Result<(), String> {
+        self.pins
+            .lock()
+            .unwrap()
+            .check(admin, fingerprint)
+            .inspect_err(|pinned| {
+                log::error!("Certificate of {admin} is {fingerprint}, but we pinned {pinned}")
+            })
+    }
+
+    /// Drop what was noted about connection `connection_id`, once it's closed
+    pub fn forget(&self, connection_id: u32) {
+        self.presented.lock().unwrap().remove(&connection_id);
+    }
+}
+
+fn fingerprint(cert: &X509Ref) -> Result<String, openssl::error::ErrorStack> {
+    let digest = cert.digest(MessageDigest::sha256())?;
+    Ok(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+fn generate_identity() -> Result<(X509, PKey<Private>), openssl::error::ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, &format!("vlawn {}", whoami::username()))?;
+    let name = name.build();
+
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = BigNum::from_u32(rand::random())?.to_asn1_integer()?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(CERT_DAYS)?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    cert.sign(&key, MessageDigest::sha256())?;
+    Ok((cert.build(), key))
+}
+
+fn load_identity(dir: &std::path::Path) -> io::Result<(X509, PKey<Private>)> {
+    let cert = X509::from_pem(&fs::read(dir.join("cert.pem"))?).map_err(io::Error::other)?;
+    let key =
+        PKey::private_key_from_pem(&fs::read(dir.join("key.pem"))?).map_err(io::Error::other)?;
+    Ok((cert, key))
+}
+
+fn save_identity(dir: &std::path::Path, (cert, key): &(X509, PKey<Private>)) -> io::Result<()> {
+    let key_pem = key.private_key_to_pem_pkcs8().map_err(io::Error::other)?;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Readable by us alone from the start, rather than after a window of being world-readable
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(dir.join("key.pem"))?.write_all(&key_pem)?;
+    fs::write(
+        dir.join("cert.pem"),
+        cert.to_pem().map_err(io::Error::other)?,
+    )
+}
+
+/// Certificate fingerprints of the admins we've connected to, one `<peer id> <fingerprint>`
+/// per line, so a pin can be dropped by hand when a peer legitimately got a new certificate
+struct Pins {
+    path: Option<PathBuf>,
+    admins: HashMap<PeerId, String>,
+}
+
+impl Pins {
+    fn load() -> Self {
+        let path = crate::paths::data_dir().map(|d| d.join("known_admins"));
+        let admins = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter_map(|line| {
+                        let (id, fingerprint) = line.split_once(' ')?;
+                        Some((id.parse().ok()?, fingerprint.trim().to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Pins { path, admins }
+    }
+
+    /// Trust `fingerprint` for `admin` if it's what we pinned, or pin it if we had nothing.
+    ///
+    /// Returns the pinned fingerprint if it differs.
+    fn check(&mut self, admin: &PeerId, fingerprint: &str) -> Result<(), String> {
+        match self.admins.get(admin) {
+            Some(pinned) if pinned == fingerprint => Ok(()),
+            Some(pinned) => Err(pinned.clone()),
+            None => {
+                log::info!("Pinning certificate {fingerprint} for {admin}");
+                self.admins.insert(*admin, fingerprint.to_string());
+                if let Err(e) = self.save() {
+                    log::warn!("Failed to store pinned certificates: {e}");
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let contents: String = self
+            .admins
+            .iter()
+            .map(|(id, fingerprint)| format!("{id} {fingerprint}\n"))
+            .collect();
+        fs::write(path, contents)
+    }
+}
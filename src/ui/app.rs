@@ -1,6 +1,13 @@
-use std::{net::IpAddr, str::FromStr, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
-use crate::entities::{Event as OurEvent, ForwardPayload, Handler, Hierarchy, StateManager};
+use super::{
+    command::{self, Command},
+    recent::{self, RecentRoom},
+};
+use crate::{
+    cli::Start,
+    config::{Config, Keys, Theme},
+};
 use color_eyre::Result;
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use ratatui::{
@@ -8,13 +15,17 @@ use ratatui::{
         self, Event, KeyCode, KeyEventKind, MouseButton, MouseEvent, MouseEventKind,
     },
     layout::{Constraint, Layout, Position, Rect},
-    style::{Color, Modifier, Style, Stylize},
+    style::{Modifier, Style, Stylize},
     symbols,
     text::{Line, Span, Text},
-    widgets::{Block, BorderType, List, ListItem, Paragraph},
+    widgets::{Block, BorderType, List, ListItem, Paragraph, Row, Table},
     DefaultTerminal, Frame,
 };
-use ws::listen;
+use vlawn::entities::{
+    Endpoint, Entry, Event as OurEvent, ForwardPayload, Heartbeat, RoomOptions, StateManager,
+    WS_PORT,
+};
+use vlawn::tls::Tls;
 
 /// App holds the state of the application
 pub struct App {
@@ -34,10 +45,32 @@ pub struct App {
     last_mouse_event: Option<MouseEvent>,
     /// Vertical scroll offset for the messages list (index of the top-most message shown)
     messages_scroll: usize,
+    /// Index of the highlighted row in the lobby
+    selected_room: usize,
+    /// Last computed area for the lobby's room table
+    lobby_area: Option<Rect>,
+    /// Rooms joined in previous sessions, most recent first
+    recent: Vec<RecentRoom>,
+    /// Last error to show in the lobby, e.g. an unresolvable host
+    lobby_status: Option<String>,
+    /// Line to show below the room's messages, e.g. help or an error from a command
+    room_status: Option<Span<'static>>,
 
     events_tx: Sender<OurEvent>,
     events_rx: Receiver<OurEvent>,
     manager: StateManager,
+    /// Address to take connections from other peers at, instead of every one
+    bind: Option<IpAddr>,
+    /// Port to take connections from other peers on
+    port: u16,
+    /// Where to go on startup
+    start: Option<Start>,
+    /// Passphrase given on startup, for the room we create or the first that challenges us
+    passphrase: Option<String>,
+    /// Room to join once it's discovered
+    looking_for: Option<String>,
+    theme: Theme,
+    keys: Keys,
 }
 
 pub enum InputMode {
@@ -45,10 +78,38 @@ pub enum InputMode {
     Editing,
 }
 
+/// A row of the lobby's room table
+struct LobbyRow {
+    name: String,
+    admin: String,
+    members: String,
+    latency: String,
+    target: LobbyTarget,
+}
+
+/// What selecting a lobby row does
+enum LobbyTarget {
+    /// Join a room discovered on the LAN
+    Join(Endpoint, String),
+    /// Resolve and join a previously used host
+    Host(String),
+    /// Create a new room with ourselves as admin
+    Create,
+}
+
 impl App {
-    pub fn new() -> Self {
+    pub fn new(
+        tls: Option<Arc<Tls>>,
+        config: &Config,
+        heartbeat: Heartbeat,
+        start: Start,
+        passphrase: Option<String>,
+    ) -> Self {
         let (events_tx, events_rx) = unbounded::<OurEvent>();
-        let manager = StateManager::new(events_tx.clone());
+        let manager = StateManager::new(events_tx.clone(), heartbeat, tls);
+        if let Some(name) = &config.name {
+            events_tx.send(OurEvent::Rename(name.clone())).unwrap();
+        }
 
         Self {
             input: String::new(),
@@ -59,10 +120,22 @@ impl App {
             input_area: None,
             last_mouse_event: None,
             messages_scroll: 0,
+            selected_room: 0,
+            lobby_area: None,
+            recent: recent::load(),
+            lobby_status: None,
+            room_status: None,
 
             events_rx,
             events_tx,
             manager,
+            bind: config.bind,
+            port: config.port.unwrap_or(WS_PORT),
+            start: Some(start),
+            passphrase,
+            looking_for: None,
+            theme: config.theme,
+            keys: config.keys,
         }
     }
 
@@ -150,23 +223,215 @@ impl App {
             self.reset_cursor();
             return;
         }
-        self.events_tx
-            .send(OurEvent::SubmitMessage(ForwardPayload::Text(
-                self.input.clone(),
-            )))
-            .unwrap();
+        self.room_status = None;
+        match self.input.trim().strip_prefix('/') {
+            Some(command) => self.run_command(command.to_string()),
+            None => self
+                .events_tx
+                .send(OurEvent::SubmitMessage(ForwardPayload::Text(
+                    self.input.clone(),
+                )))
+                .unwrap(),
+        }
         self.input.clear();
         self.reset_cursor();
-        // scroll to bottom when a new message is submitted
-        // let inner_height = self
-        //     .messages_area
-        //     .map(|a| a.height.saturating_sub(2) as usize)
-        //     .unwrap_or(0);
-        // if inner_height > 0 && self.messages.len() > inner_height {
-        //     self.messages_scroll = self.messages.len() - inner_height;
-        // } else {
-        //     self.messages_scroll = 0;
-        // }
+    }
+
+    /// Carry out a command typed as `/name argument`
+    fn run_command(&mut self, line: String) {
+        let event = match command::parse(&line) {
+            Ok(Command::Help(None)) => {
+                let names: Vec<String> = command::COMMANDS
+                    .iter()
+                    .map(|spec| format!("/{}", spec.name))
+                    .collect();
+                let help = format!("{}, /help <command> for more", names.join(" "));
+                self.room_status = Some(help.yellow());
+                return;
+            }
+            Ok(Command::Help(Some(spec))) => {
+                self.room_status = Some(format!("{}: {}", spec.usage(), spec.help).yellow());
+                return;
+            }
+            Ok(Command::Join(host)) => {
+                self.join_host(&host);
+                self.room_status = self
+                    .lobby_status
+                    .clone()
+                    .map(|status| status.fg(self.theme.error));
+                return;
+            }
+            Ok(Command::Nick(username)) => OurEvent::Rename(username),
+            Ok(Command::Me(action)) => OurEvent::SubmitMessage(ForwardPayload::Action(action)),
+            Ok(Command::Topic(topic)) => OurEvent::SetTopic(topic),
+            Ok(Command::Leave) => OurEvent::Part,
+            Ok(Command::Quit) => OurEvent::Leave,
+            Ok(Command::Invite) => OurEvent::CreateInvite,
+            Ok(Command::Moderate(sanction, target)) => OurEvent::Moderate(sanction, target),
+            Err(e) => {
+                self.room_status = Some(e.fg(self.theme.error));
+                return;
+            }
+        };
+        self.events_tx.send(event).unwrap();
+    }
+
+    /// Complete the command or member name being typed, listing the candidates if there
+    /// are several
+    fn complete_command(&mut self) {
+        let Some(line) = self.input.strip_prefix('/') else {
+            return;
+        };
+        let peers: Vec<String> = self
+            .manager
+            .peers()
+            .map(|hierarchy| hierarchy.0.iter().map(|p| p.username().clone()).collect())
+            .unwrap_or_default();
+        let banned: Vec<String> = self
+            .manager
+            .moderation()
+            .map(|m| m.banned().map(|ban| ban.peer.username().clone()).collect())
+            .unwrap_or_default();
+        let names: Vec<&str> = peers.iter().chain(&banned).map(String::as_str).collect();
+
+        let (completed, candidates) = command::complete(line, &names);
+        if let Some(completed) = completed {
+            self.input = completed;
+            self.character_index = self.input.chars().count();
+        }
+        if !candidates.is_empty() {
+            self.room_status = Some(candidates.join(" ").yellow());
+        }
+    }
+
+    /// Handle a key pressed while typing
+    fn edit(&mut self, code: KeyCode) {
+        match code {
+            KeyCode::Enter if self.in_lobby() => {
+                let host = std::mem::take(&mut self.input);
+                self.reset_cursor();
+                if !host.trim().is_empty() {
+                    self.join_host(&host);
+                }
+            }
+            KeyCode::Enter if self.manager.awaiting_secret() => {
+                let secret = std::mem::take(&mut self.input);
+                self.reset_cursor();
+                self.events_tx.send(OurEvent::Secret(secret)).unwrap();
+            }
+            KeyCode::Enter => self.submit_message(),
+            KeyCode::Tab if self.input.starts_with('/') && !self.in_lobby() => {
+                self.complete_command()
+            }
+            // Bindings go first, as they may be characters as well
+            code if code == self.keys.typing => self.input_mode = InputMode::Normal,
+            KeyCode::Char(to_insert) => self.enter_char(to_insert),
+            KeyCode::Backspace => self.delete_char(),
+            KeyCode::Delete => self.delete_forward(),
+            KeyCode::Left => self.move_cursor_left(),
+            KeyCode::Right => self.move_cursor_right(),
+            _ => {}
+        }
+    }
+
+    fn in_lobby(&self) -> bool {
+        self.manager.discovered().is_some()
+    }
+
+    /// Discovered rooms, followed by recent rooms that aren't currently announced
+    fn lobby_rows(&self) -> Vec<LobbyRow> {
+        let discovered = self.manager.discovered().map(Vec::as_slice).unwrap_or(&[]);
+
+        let mut rows: Vec<LobbyRow> = discovered
+            .iter()
+            .map(|room| LobbyRow {
+                name: room.beacon.name.clone(),
+                admin: room.beacon.admin.username().clone(),
+                members: room.beacon.members.to_string(),
+                latency: room
+                    .latency
+                    .map(|l| format!("{}ms", l.as_millis()))
+                    .unwrap_or_else(|| "?".into()),
+                target: LobbyTarget::Join(room.endpoint(), room.beacon.name.clone()),
+            })
+            .collect();
+
+        rows.extend(
+            self.recent
+                .iter()
+                .filter(|recent| {
+                    !discovered.iter().any(|room| {
+                        recent.host == room.endpoint().to_string()
+                            || recent.name.as_ref() == Some(&room.beacon.name)
+                    })
+                })
+                .map(|recent| LobbyRow {
+                    name: recent.name.clone().unwrap_or_else(|| recent.host.clone()),
+                    admin: recent.host.clone(),
+                    members: "-".into(),
+                    latency: "recent".into(),
+                    target: LobbyTarget::Host(recent.host.clone()),
+                }),
+        );
+
+        rows.push(LobbyRow {
+            name: "+ new room".into(),
+            admin: String::new(),
+            members: String::new(),
+            latency: String::new(),
+            target: LobbyTarget::Create,
+        });
+        rows
+    }
+
+    fn activate_lobby_row(&mut self, index: usize) {
+        let Some(row) = self.lobby_rows().into_iter().nth(index) else {
+            return;
+        };
+        match row.target {
+            LobbyTarget::Join(endpoint, name) => {
+                recent::record(
+                    &mut self.recent,
+                    RecentRoom {
+                        name: Some(name),
+                        host: endpoint.to_string(),
+                    },
+                );
+                self.events_tx.send(OurEvent::JoinSend(endpoint)).unwrap();
+            }
+            LobbyTarget::Host(host) => self.join_host(&host),
+            LobbyTarget::Create => self
+                .events_tx
+                .send(OurEvent::StartRoom(RoomOptions::default()))
+                .unwrap(),
+        }
+    }
+
+    /// Resolve a manually entered (or remembered) host and join it
+    fn join_host(&mut self, host: &str) {
+        let host = host.trim();
+        match Endpoint::resolve(host) {
+            Ok(endpoint) => self.join_endpoint(host, endpoint),
+            Err(e) => self.lobby_status = Some(format!("Could not resolve {host}: {e}")),
+        }
+    }
+
+    /// Join the room at `endpoint`, remembering it as `host`
+    fn join_endpoint(&mut self, host: &str, endpoint: Endpoint) {
+        let name = self
+            .recent
+            .iter()
+            .find(|r| r.host == host)
+            .and_then(|r| r.name.clone());
+        recent::record(
+            &mut self.recent,
+            RecentRoom {
+                name,
+                host: host.to_string(),
+            },
+        );
+        self.lobby_status = None;
+        self.events_tx.send(OurEvent::JoinSend(endpoint)).unwrap();
     }
 
     pub fn run(mut self, mut terminal: DefaultTerminal) -> Result<()> {
@@ -174,32 +439,29 @@ impl App {
         crossterm::terminal::enable_raw_mode()?;
         crossterm::execute!(std::io::stdout(), crossterm::event::EnableMouseCapture)?;
 
-        let events_tx = self.events_tx.clone();
-
-        std::thread::Builder::new()
-            .name("websocket server".into())
-            .spawn(move || {
-                log::info!("Websocket server started");
-                listen("0.0.0.0:57185", |out| {
-                    log::info!("New connection");
-                    let connection_id = out.connection_id();
-                    events_tx.send(OurEvent::Open(out)).unwrap();
-                    Handler::new(events_tx.clone(), connection_id)
-                })
-                .expect("listener failed");
-            })
-            .unwrap();
-
-        match std::env::args().nth(1) {
-            Some(host) => {
-                // let ip_addr = IpAddr::from_str(&addr).unwrap();
-                let mut ips = dns_lookup::lookup_host(&host).unwrap();
-                self.events_tx
-                    .send(OurEvent::JoinSend(ips.nth(0).unwrap()))
-                    .unwrap();
+        self.manager.listen(self.bind, self.port);
+
+        match self.start.take() {
+            Some(Start::Host(options)) => {
+                let options = RoomOptions {
+                    passphrase: options.passphrase.or(self.passphrase.take()),
+                    ..options
+                };
+                self.events_tx.send(OurEvent::StartRoom(options)).unwrap();
             }
-            _ => {
-                self.events_tx.send(OurEvent::StartRoom).unwrap();
+            Some(Start::Join { host, endpoint }) => self.join_endpoint(&host, endpoint),
+            Some(Start::Find { name, unresolved }) => {
+                self.lobby_status = Some(match unresolved {
+                    Some(e) => {
+                        format!("{name} isn't a host ({e}), looking for a room by that name")
+                    }
+                    None => format!("Looking for {name}"),
+                });
+                self.looking_for = Some(name);
+                self.events_tx.send(OurEvent::Discover).unwrap();
+            }
+            Some(Start::Lobby) | None => {
+                self.events_tx.send(OurEvent::Discover).unwrap();
             }
         }
 
@@ -208,11 +470,71 @@ impl App {
             terminal.draw(|frame| self.draw(frame))?;
 
             // read an input event (keyboard or mouse)
+            let keys = self.keys;
             if let Ok(true) = event::poll(Duration::from_millis(10)) {
                 match event::read()? {
                     Event::Key(key) => match self.input_mode {
+                        InputMode::Normal if self.in_lobby() => {
+                            let row_count = self.lobby_rows().len();
+                            match key.code {
+                                code if code == keys.up => {
+                                    self.selected_room = self.selected_room.saturating_sub(1);
+                                }
+                                code if code == keys.down => {
+                                    self.selected_room =
+                                        (self.selected_room + 1).min(row_count.saturating_sub(1));
+                                }
+                                code if code == keys.join => {
+                                    self.activate_lobby_row(self.selected_room)
+                                }
+                                code if code == keys.new_room => {
+                                    self.events_tx
+                                        .send(OurEvent::StartRoom(RoomOptions::default()))
+                                        .unwrap();
+                                }
+                                code if code == keys.blind_room => {
+                                    // host a room we relay but can't read ourselves
+                                    let options = RoomOptions {
+                                        blind: true,
+                                        ..RoomOptions::default()
+                                    };
+                                    self.events_tx.send(OurEvent::StartRoom(options)).unwrap();
+                                }
+                                code if code == keys.protected_room => {
+                                    // protect the new room with whatever was typed
+                                    let passphrase = std::mem::take(&mut self.input);
+                                    self.reset_cursor();
+                                    if passphrase.trim().is_empty() {
+                                        self.lobby_status = Some(format!(
+                                            "Type a passphrase first, then press {}",
+                                            keys.protected_room
+                                        ));
+                                    } else {
+                                        let options = RoomOptions {
+                                            passphrase: Some(passphrase),
+                                            ..RoomOptions::default()
+                                        };
+                                        self.events_tx.send(OurEvent::StartRoom(options)).unwrap();
+                                    }
+                                }
+                                code if code == keys.invite_only_room => {
+                                    let options = RoomOptions {
+                                        invite_only: true,
+                                        ..RoomOptions::default()
+                                    };
+                                    self.events_tx.send(OurEvent::StartRoom(options)).unwrap();
+                                }
+                                code if code == keys.typing => {
+                                    self.input_mode = InputMode::Editing;
+                                }
+                                code if code == keys.quit => {
+                                    self.events_tx.send(OurEvent::Leave).unwrap()
+                                }
+                                _ => {}
+                            }
+                        }
                         InputMode::Normal => match key.code {
-                            KeyCode::Up => {
+                            code if code == keys.up => {
                                 // scroll up one line in the messages view
                                 if let Some(area) = self.messages_area {
                                     let inner = area.height.saturating_sub(2) as usize;
@@ -222,11 +544,11 @@ impl App {
                                     }
                                 }
                             }
-                            KeyCode::Down => {
+                            code if code == keys.down => {
                                 // scroll down one line in the messages view
                                 if let Some(area) = self.messages_area {
                                     let inner = area.height.saturating_sub(2) as usize;
-                                    let hist_len = self.manager.history().len();
+                                    let hist_len = self.manager.timeline().len();
                                     if inner > 0 && hist_len > inner {
                                         let max_start = hist_len - inner;
                                         self.messages_scroll =
@@ -234,33 +556,21 @@ impl App {
                                     }
                                 }
                             }
-                            // KeyCode::Char('e') | KeyCode::Tab => {
-                            KeyCode::Tab => {
+                            code if code == keys.typing => {
                                 self.input_mode = InputMode::Editing;
                             }
-                            // KeyCode::Char('q') | KeyCode::Esc => {
-                            KeyCode::Esc => {
-                                // disable mouse capture and raw mode before exiting
-                                crossterm::execute!(
-                                    std::io::stdout(),
-                                    crossterm::event::DisableMouseCapture
-                                )?;
-                                crossterm::terminal::disable_raw_mode()?;
-                                return Ok(());
+                            code if code == keys.invite => {
+                                self.events_tx.send(OurEvent::CreateInvite).unwrap();
+                            }
+                            code if code == keys.quit => {
+                                // say goodbye to the room, we exit once that went out
+                                self.events_tx.send(OurEvent::Leave).unwrap();
                             }
                             _ => {}
                         },
-                        InputMode::Editing if key.kind == KeyEventKind::Press => match key.code {
-                            KeyCode::Enter => self.submit_message(),
-                            KeyCode::Char(to_insert) => self.enter_char(to_insert),
-                            KeyCode::Backspace => self.delete_char(),
-                            KeyCode::Delete => self.delete_forward(),
-                            KeyCode::Left => self.move_cursor_left(),
-                            KeyCode::Right => self.move_cursor_right(),
-                            // KeyCode::Esc | KeyCode::Tab => self.input_mode = InputMode::Normal,
-                            KeyCode::Tab => self.input_mode = InputMode::Normal,
-                            _ => {}
-                        },
+                        InputMode::Editing if key.kind == KeyEventKind::Press => {
+                            self.edit(key.code)
+                        }
                         InputMode::Editing => {}
                     },
                     Event::Mouse(me) => {
@@ -276,15 +586,34 @@ impl App {
                 log::info!("Received event: {event:?}");
                 self.manager.handle(event);
             }
+
+            // The passphrase we were given is tried once, after that it's asked for
+            if self.manager.awaiting_secret() {
+                if let Some(secret) = self.passphrase.take() {
+                    self.events_tx.send(OurEvent::Secret(secret)).unwrap();
+                }
+            }
+
+            if let Some(room) = &self.looking_for {
+                if let Some(endpoint) = self.manager.discovered_room(room) {
+                    self.events_tx.send(OurEvent::JoinSend(endpoint)).unwrap();
+                    self.looking_for = None;
+                    self.lobby_status = None;
+                }
+            }
+
+            if self.manager.has_left() {
+                // disable mouse capture and raw mode before exiting
+                crossterm::execute!(std::io::stdout(), crossterm::event::DisableMouseCapture)?;
+                crossterm::terminal::disable_raw_mode()?;
+                return Ok(());
+            }
         }
     }
 
     fn draw(&mut self, frame: &mut Frame) {
-        let fgcolor = Color::Indexed(229);
-        let bgcolor = Color::Indexed(235);
-        let brightgreen = Color::Indexed(40);
-
-        let hist_len = self.manager.history().len();
+        let theme = self.theme;
+        let keys = self.keys;
 
         let outer_block = Block::bordered()
             .border_type(BorderType::Double)
@@ -292,8 +621,8 @@ impl App {
             .title("memory".cyan().italic())
             .title("hoping".red().italic())
             .title(Line::from(" vlawn ").right_aligned().green().bold())
-            .fg(fgcolor)
-            .bg(bgcolor);
+            .fg(theme.foreground)
+            .bg(theme.background);
         let outer_area = frame.area();
 
         frame.render_widget(outer_block.clone(), outer_area);
@@ -319,29 +648,47 @@ impl App {
         self.input_area = Some(input_area);
 
         let (msg, style) = match self.input_mode {
+            InputMode::Normal if self.in_lobby() => (
+                vec![
+                    keys.join.to_string().bold(),
+                    " join, ".into(),
+                    keys.new_room.to_string().bold(),
+                    " new room, ".into(),
+                    keys.blind_room.to_string().bold(),
+                    " blind room, ".into(),
+                    keys.protected_room.to_string().bold(),
+                    " room with typed passphrase, ".into(),
+                    keys.invite_only_room.to_string().bold(),
+                    " invite-only room, ".into(),
+                    keys.typing.to_string().bold(),
+                    " type host.".into(),
+                ],
+                Style::default(),
+            ),
             InputMode::Normal => (
                 vec![
                     "Press ".into(),
-                    // "q".bold(),
-                    // " or ".into(),
-                    "Esc".bold(),
+                    keys.quit.to_string().bold(),
                     " to exit, ".into(),
-                    // "e".bold(),
-                    // " or ".into(),
-                    "Tab".bold(),
-                    " to start typing.".into(),
+                    keys.typing.to_string().bold(),
+                    " to start typing".into(),
+                    if self.manager.invites().is_some() {
+                        format!(", {} to create an invite code.", keys.invite).into()
+                    } else {
+                        ".".into()
+                    },
                 ],
                 Style::default().add_modifier(Modifier::SLOW_BLINK),
             ),
             InputMode::Editing => (
                 vec![
                     "Press ".into(),
-                    // "Esc".bold(),
-                    // " or ".into(),
-                    "Tab".bold(),
+                    keys.typing.to_string().bold(),
                     " to stop typing, ".into(),
                     "Enter".bold(),
-                    " to send.".into(),
+                    " to send, ".into(),
+                    "/help".bold(),
+                    " for commands.".into(),
                 ],
                 Style::default(),
             ),
@@ -353,10 +700,105 @@ impl App {
         let grass_message: Paragraph<'_> = Paragraph::new(
             Line::from("\\|/\\|/.,.,\\(/,,..,.,\\|/\\)/\\)/,,,\\,/..,.//(.,,.,\\.)")
                 .right_aligned()
-                .fg(brightgreen),
+                .fg(theme.grass),
         );
         frame.render_widget(grass_message, grass_top);
 
+        if self.in_lobby() {
+            self.draw_lobby(frame, focus_area);
+        } else {
+            self.draw_room(frame, messages_area, members_area);
+        }
+
+        // Widget-level mouse handling for messages/input
+        if let Some(me) = &self.last_mouse_event {
+            match &me.kind {
+                MouseEventKind::Down(btn) if *btn == MouseButton::Left => {
+                    let col = me.column as i32;
+                    let row = me.row as i32;
+                    if col >= messages_area.x as i32
+                        && col < (messages_area.x + messages_area.width) as i32
+                        && row >= messages_area.y as i32
+                        && row < (messages_area.y + messages_area.height) as i32
+                    {
+                        // Click inside messages: deselect input
+                        self.input_mode = InputMode::Normal;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let bottom_border_set = symbols::border::Set {
+            horizontal_bottom: "෴",
+            ..symbols::border::PLAIN
+        };
+
+        let input_block =
+            Block::bordered()
+                .border_set(bottom_border_set)
+                .title(if self.in_lobby() {
+                    "Host".bold()
+                } else if self.manager.awaiting_secret() {
+                    "Passphrase or invite code".bold()
+                } else {
+                    "Input".bold()
+                }); // ෴🌱﹌♒︎﹏
+        let input = Paragraph::new(self.input.as_str())
+            .style(match self.input_mode {
+                InputMode::Normal => Style::default(),
+                InputMode::Editing => Style::default().fg(theme.typing),
+            })
+            .block(input_block.clone());
+        frame.render_widget(input, input_area);
+
+        // Widget-level mouse handling for input (position cursor and set editing)
+        if let Some(me) = &self.last_mouse_event {
+            match &me.kind {
+                MouseEventKind::Down(btn) if *btn == MouseButton::Left => {
+                    let col = me.column as i32;
+                    let row = me.row as i32;
+                    if col >= input_area.x as i32
+                        && col < (input_area.x + input_area.width) as i32
+                        && row >= input_area.y as i32
+                        && row < (input_area.y + input_area.height) as i32
+                    {
+                        // set editing mode and position cursor
+                        self.input_mode = InputMode::Editing;
+                        let char_pos = (col - input_area.x as i32 - 1).max(0) as usize;
+                        let clamped = char_pos.clamp(0, self.input.chars().count());
+                        self.character_index = clamped;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        // clear the mouse event after widgets had a chance to handle it
+        self.last_mouse_event = None;
+        match self.input_mode {
+            // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
+            InputMode::Normal => {}
+
+            // Make the cursor visible and ask ratatui to put it at the specified coordinates after
+            // rendering
+            #[allow(clippy::cast_possible_truncation)]
+            InputMode::Editing => frame.set_cursor_position(Position::new(
+                // Draw the cursor at the current position in the input field.
+                // This position is can be controlled via the left and right arrow key
+                input_area.x + self.character_index as u16 + 1,
+                // Move one line down, from the border to the input line
+                input_area.y + 1,
+            )),
+        }
+    }
+
+    /// Render the message history and member list of the joined room
+    fn draw_room(&mut self, frame: &mut Frame, messages_area: Rect, members_area: Rect) {
+        let theme = self.theme;
+        let timeline = self.manager.timeline();
+        let hist_len = timeline.len();
+
         // Handle scroll events (mouse wheel) that were captured by the event loop
         if let Some(me) = &self.last_mouse_event {
             match &me.kind {
@@ -400,117 +842,202 @@ impl App {
         let visible_messages = if visible_count == 0 {
             Vec::new()
         } else {
-            self.manager
-                .history()
+            timeline
                 .iter()
                 .skip(start_idx)
                 .take(visible_count)
-                .map(|(peer, payload)| {
-                    let content = match payload {
-                        ForwardPayload::Text(str) => {
-                            Line::from(Span::raw(format!("{}: {}", peer.username(), str)))
+                .map(|entry| {
+                    let envelope = match entry {
+                        Entry::Message(envelope) => envelope,
+                        Entry::Notice(notice) => {
+                            // Our own notices look like the admin's, errors stand out
+                            let text = if notice.is_error {
+                                Span::raw(format!(" ! {}", notice.text)).fg(theme.error)
+                            } else {
+                                Span::raw(format!(" — {}", notice.text)).fg(theme.notice)
+                            };
+                            return ListItem::new(Line::from(vec![
+                                Span::raw(clock(notice.at)).fg(theme.dim),
+                                text.italic(),
+                            ]));
                         }
-                        _ => todo!(),
+                    };
+                    let sent_at = clock(envelope.sent_at);
+                    let content = match self.manager.reveal(envelope) {
+                        Some(ForwardPayload::Text(str)) => Line::from(vec![
+                            Span::raw(sent_at).fg(theme.dim),
+                            Span::raw(format!(" {}: {}", envelope.sender.username(), str)),
+                        ]),
+                        // System events announced by the admin
+                        Some(ForwardPayload::Notification(str)) => Line::from(vec![
+                            Span::raw(sent_at).fg(theme.dim),
+                            Span::raw(format!(" — {str}")).fg(theme.notice).italic(),
+                        ]),
+                        Some(ForwardPayload::Action(str)) => Line::from(vec![
+                            Span::raw(sent_at).fg(theme.dim),
+                            Span::raw(format!(" * {} {str}", envelope.sender.username())).italic(),
+                        ]),
+                        // Sent before we joined, or we're relaying a room we can't read
+                        Some(ForwardPayload::Sealed(_)) | None => Line::from(vec![
+                            Span::raw(sent_at).fg(theme.dim),
+                            Span::raw(format!(
+                                " {}: <encrypted message>",
+                                envelope.sender.username()
+                            ))
+                            .fg(theme.dim),
+                        ]),
                     };
                     ListItem::new(content)
                 })
                 .collect()
         };
 
-        let messages_block = Block::bordered().title("Messages".bold());
+        let mut messages_block = Block::bordered().title("Messages".bold());
+        if let Some(topic) = self.manager.topic() {
+            messages_block = messages_block.title(format!(" {topic}").fg(theme.dim));
+        }
+        if let Some(status) = &self.room_status {
+            messages_block = messages_block.title_bottom(status.clone());
+        }
         let messages_widget = List::new(visible_messages).block(messages_block.clone());
         frame.render_widget(messages_widget, messages_area);
 
-        let empty_hierarchy = Hierarchy::new();
         // Render members list in the right hand column
         let members_items: Vec<ListItem> = self
             .manager
             .peers()
-            .unwrap_or(&empty_hierarchy)
-            .0
+            .map(|hierarchy| hierarchy.0.as_slice())
+            .unwrap_or_default()
             .iter()
-            .map(|peer| ListItem::new(Line::from(Span::raw(peer.username()))))
-            .collect(); // TODO if is admin
-        let members_block = Block::bordered().title("Members".bold());
+            .map(|peer| {
+                // the fingerprint tells apart peers with the same name
+                let mut spans = vec![
+                    Span::raw(peer.username()),
+                    Span::raw(format!(" {}", peer.id().short())).fg(theme.dim),
+                ];
+                if self
+                    .manager
+                    .moderation()
+                    .is_some_and(|m| m.is_muted(peer.id()))
+                {
+                    spans.push(Span::raw(" muted").fg(theme.error));
+                }
+                ListItem::new(Line::from(spans))
+            })
+            .collect();
+        let mut members_block = Block::bordered().title("Members".bold());
+        for code in self.manager.invites().unwrap_or_default() {
+            members_block = members_block.title_bottom(format!("Invite {code}").fg(theme.dim));
+        }
         let members_widget = List::new(members_items).block(members_block.clone());
         frame.render_widget(members_widget, members_area);
+    }
 
-        // Widget-level mouse handling for messages/input
-        if let Some(me) = &self.last_mouse_event {
-            match &me.kind {
-                MouseEventKind::Down(btn) if *btn == MouseButton::Left => {
-                    let col = me.column as i32;
-                    let row = me.row as i32;
-                    if col >= messages_area.x as i32
-                        && col < (messages_area.x + messages_area.width) as i32
-                        && row >= messages_area.y as i32
-                        && row < (messages_area.y + messages_area.height) as i32
-                    {
-                        // Click inside messages: deselect input
-                        self.input_mode = InputMode::Normal;
-                    }
-                }
-                _ => {}
-            }
+    /// Render the table of discovered and recent rooms
+    fn draw_lobby(&mut self, frame: &mut Frame, area: Rect) {
+        let theme = self.theme;
+        if let Some(error) = self.manager.take_error() {
+            self.lobby_status = Some(error);
+        }
+        if let Some(reason) = self.manager.take_rejection() {
+            self.lobby_status = Some(format!("Could not join: {reason}"));
         }
 
-        let bottom_border_set = symbols::border::Set {
-            horizontal_bottom: "෴",
-            // horizontal_bottom: "^",
-            // horizontal_bottom: "\"",
-            ..symbols::border::PLAIN
-        };
-
-        let input_block = Block::bordered()
-            // .borders(Borders::TOP | Borders::LEFT | Borders::RIGHT)
-            .border_set(bottom_border_set)
-            .title("Input".bold()); // ෴🌱﹌♒︎﹏
-        let input = Paragraph::new(self.input.as_str())
-            .style(match self.input_mode {
-                InputMode::Normal => Style::default(),
-                InputMode::Editing => Style::default().fg(Color::LightGreen),
-            })
-            .block(input_block.clone());
-        frame.render_widget(input, input_area);
+        let rows = self.lobby_rows();
+        self.selected_room = self.selected_room.min(rows.len().saturating_sub(1));
+        self.lobby_area = Some(area);
 
-        // Widget-level mouse handling for input (position cursor and set editing)
+        // Widget-level mouse handling: click selects a row, clicking it again activates it
         if let Some(me) = &self.last_mouse_event {
             match &me.kind {
                 MouseEventKind::Down(btn) if *btn == MouseButton::Left => {
                     let col = me.column as i32;
                     let row = me.row as i32;
-                    if col >= input_area.x as i32
-                        && col < (input_area.x + input_area.width) as i32
-                        && row >= input_area.y as i32
-                        && row < (input_area.y + input_area.height) as i32
+                    // skip the top border and the header row
+                    let first_row = area.y as i32 + 2;
+                    if col >= area.x as i32
+                        && col < (area.x + area.width) as i32
+                        && row >= first_row
+                        && row < (area.y + area.height) as i32 - 1
                     {
-                        // set editing mode and position cursor
-                        self.input_mode = InputMode::Editing;
-                        let char_pos = (col - input_area.x as i32 - 1).max(0) as usize;
-                        let clamped = char_pos.clamp(0, self.input.chars().count());
-                        self.character_index = clamped;
+                        let index = (row - first_row) as usize;
+                        if index == self.selected_room {
+                            self.activate_lobby_row(index);
+                        } else if index < rows.len() {
+                            self.selected_room = index;
+                        }
                     }
                 }
+                MouseEventKind::ScrollUp => {
+                    self.selected_room = self.selected_room.saturating_sub(1);
+                }
+                MouseEventKind::ScrollDown => {
+                    self.selected_room = (self.selected_room + 1).min(rows.len().saturating_sub(1));
+                }
                 _ => {}
             }
         }
 
-        // clear the mouse event after widgets had a chance to handle it
-        self.last_mouse_event = None;
-        match self.input_mode {
-            // Hide the cursor. `Frame` does this by default, so we don't need to do anything here
-            InputMode::Normal => {}
+        let table_rows: Vec<Row> = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, row)| {
+                let table_row = Row::new([row.name, row.admin, row.members, row.latency]);
+                if i == self.selected_room {
+                    table_row.reversed()
+                } else {
+                    table_row
+                }
+            })
+            .collect();
 
-            // Make the cursor visible and ask ratatui to put it at the specified coordinates after
-            // rendering
-            #[allow(clippy::cast_possible_truncation)]
-            InputMode::Editing => frame.set_cursor_position(Position::new(
-                // Draw the cursor at the current position in the input field.
-                // This position is can be controlled via the left and right arrow key
-                input_area.x + self.character_index as u16 + 1,
-                // Move one line down, from the border to the input line
-                input_area.y + 1,
-            )),
+        let mut lobby_block = Block::bordered().title("Rooms".bold());
+        if let Some(tls) = self.manager.tls() {
+            // so people can compare fingerprints before trusting each other's certificates
+            lobby_block = lobby_block.title(
+                Line::from(format!("TLS {}", &tls.fingerprint()[..16]))
+                    .fg(theme.dim)
+                    .right_aligned(),
+            );
+        }
+        if let Some(status) = &self.lobby_status {
+            lobby_block = lobby_block.title_bottom(status.clone().fg(theme.error));
         }
+        let table = Table::new(
+            table_rows,
+            [
+                Constraint::Fill(1),
+                Constraint::Length(16),
+                Constraint::Length(8),
+                Constraint::Length(8),
+            ],
+        )
+        .header(Row::new(["Room", "Admin", "Members", "Latency"]).bold())
+        .block(lobby_block);
+        frame.render_widget(table, area);
+    }
+}
+
+/// Local time of day of a timestamp in milliseconds since the unix epoch
+fn clock(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .map(|t| t.with_timezone(&chrono::Local).format("%H:%M").to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_binding_wins_over_the_character() {
+        let config: Config = toml::from_str("[keys]\ntyping = \"`\"").unwrap();
+        let mut app = App::new(None, &config, Heartbeat::default(), Start::Lobby, None);
+        app.input_mode = InputMode::Editing;
+
+        app.edit(KeyCode::Char('a'));
+        app.edit(KeyCode::Char('`'));
+        assert_eq!(app.input, "a");
+        assert!(matches!(app.input_mode, InputMode::Normal));
     }
 }
@@ -0,0 +1,304 @@
+//! Commands typed into the input box as `/name argument`.
+
+use vlawn::entities::Sanction;
+
+/// Longest name a peer may go by
+const MAX_NICK_LEN: usize = 32;
+
+/// What a command takes after its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arg {
+    None,
+    /// Free text, which may contain spaces
+    Text,
+    /// A single word
+    Word,
+    /// A member of the room, by name or fingerprint
+    Peer,
+    /// A command name, which may be left out
+    Command,
+}
+
+/// Name, argument and help of a command
+pub struct Spec {
+    pub name: &'static str,
+    arg: Arg,
+    /// Placeholder for the argument in the usage line
+    placeholder: &'static str,
+    pub help: &'static str,
+}
+
+impl Spec {
+    pub fn usage(&self) -> String {
+        match self.arg {
+            Arg::None => format!("/{}", self.name),
+            Arg::Command => format!("/{} [{}]", self.name, self.placeholder),
+            _ => format!("/{} <{}>", self.name, self.placeholder),
+        }
+    }
+
+    /// Whether the argument names a member, and can be completed as such
+    pub fn takes_peer(&self) -> bool {
+        self.arg == Arg::Peer
+    }
+}
+
+const fn spec(name: &'static str, arg: Arg, placeholder: &'static str, help: &'static str) -> Spec {
+    Spec {
+        name,
+        arg,
+        placeholder,
+        help,
+    }
+}
+
+pub const COMMANDS: &[Spec] = &[
+    spec(
+        "help",
+        Arg::Command,
+        "command",
+        "List commands, or explain one",
+    ),
+    spec(
+        "nick",
+        Arg::Word,
+        "name",
+        "Change the name others see you by",
+    ),
+    spec("me", Arg::Text, "action", "Describe what you're doing"),
+    spec("topic", Arg::Text, "topic", "Set the topic of the room"),
+    spec(
+        "join",
+        Arg::Word,
+        "host",
+        "Leave this room for the one at a host",
+    ),
+    spec("leave", Arg::None, "", "Leave the room for the lobby"),
+    spec("quit", Arg::None, "", "Leave the room and exit"),
+    spec(
+        "invite",
+        Arg::None,
+        "",
+        "Create a one-time invite code, as the admin of a protected room",
+    ),
+    spec(
+        "kick",
+        Arg::Peer,
+        "peer",
+        "Drop a member from the room, letting it rejoin",
+    ),
+    spec(
+        "ban",
+        Arg::Peer,
+        "peer",
+        "Drop a member from the room, and keep it out",
+    ),
+    spec("unban", Arg::Peer, "peer", "Let a banned peer back in"),
+    spec(
+        "mute",
+        Arg::Peer,
+        "peer",
+        "Stop relaying a member's messages",
+    ),
+    spec(
+        "unmute",
+        Arg::Peer,
+        "peer",
+        "Relay a muted member's messages again",
+    ),
+];
+
+pub fn find(name: &str) -> Option<&'static Spec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/// A command, parsed and checked
+pub enum Command {
+    Help(Option<&'static Spec>),
+    Nick(String),
+    Me(String),
+    Topic(String),
+    Join(String),
+    Leave,
+    Quit,
+    Invite,
+    Moderate(Sanction, String),
+}
+
+/// Parse `line`, which follows the leading `/`
+pub fn parse(line: &str) -> Result<Command, String> {
+    let (name, arg) = line.split_once(' ').unwrap_or((line, ""));
+    let arg = arg.trim();
+    let spec = find(name).ok_or_else(|| format!("Unknown command /{name}, try /help"))?;
+    let usage = || format!("Usage: {}", spec.usage());
+
+    match spec.arg {
+        Arg::None if !arg.is_empty() => return Err(usage()),
+        Arg::Text | Arg::Word | Arg::Peer if arg.is_empty() => return Err(usage()),
+        Arg::Word | Arg::Peer | Arg::Command if arg.contains(char::is_whitespace) => {
+            return Err(usage())
+        }
+        _ => {}
+    }
+
+    let arg = arg.to_string();
+    Ok(match spec.name {
+        "help" if arg.is_empty() => Command::Help(None),
+        "help" => Command::Help(Some(
+            find(arg.trim_start_matches('/'))
+                .ok_or_else(|| format!("Unknown command /{arg}, try /help"))?,
+        )),
+        "nick" if arg.chars().count() > MAX_NICK_LEN => {
+            return Err(format!(
+                "Names can't be longer than {MAX_NICK_LEN} characters"
+            ))
+        }
+        "nick" => Command::Nick(arg),
+        "me" => Command::Me(arg),
+        "topic" => Command::Topic(arg),
+        "join" => Command::Join(arg),
+        "leave" => Command::Leave,
+        "quit" => Command::Quit,
+        "invite" => Command::Invite,
+        "kick" => Command::Moderate(Sanction::Kick, arg),
+        "ban" => Command::Moderate(Sanction::Ban, arg),
+        "unban" => Command::Moderate(Sanction::Unban, arg),
+        "mute" => Command::Moderate(Sanction::Mute, arg),
+        "unmute" => Command::Moderate(Sanction::Unmute, arg),
+        name => unreachable!("command /{name} isn't handled"),
+    })
+}
+
+/// Text replacing the input `line`, which follows the leading `/`, once completed with a
+/// command name or one of `peers`, along with the candidates if there are several
+pub fn complete(line: &str, peers: &[&str]) -> (Option<String>, Vec<String>) {
+    let (prefix, candidates): (String, Vec<String>) = match line.split_once(' ') {
+        None => (
+            "/".to_string(),
+            COMMANDS
+                .iter()
+                .filter(|spec| spec.name.starts_with(line))
+                .map(|spec| spec.name.to_string())
+                .collect(),
+        ),
+        Some((name, arg)) if !arg.contains(' ') => {
+            let candidates: Vec<String> = match find(name) {
+                Some(spec) if spec.takes_peer() => peers
+                    .iter()
+                    .filter(|peer| peer.starts_with(arg))
+                    .map(|peer| peer.to_string())
+                    .collect(),
+                Some(spec) if spec.arg == Arg::Command => COMMANDS
+                    .iter()
+                    .filter(|spec| spec.name.starts_with(arg))
+                    .map(|spec| spec.name.to_string())
+                    .collect(),
+                _ => Vec::new(),
+            };
+            (format!("/{name} "), candidates)
+        }
+        Some(_) => return (None, Vec::new()),
+    };
+
+    match candidates.as_slice() {
+        [] => (None, candidates),
+        [only] => (Some(format!("{prefix}{only} ")), Vec::new()),
+        [first, rest @ ..] => {
+            // Extend to whatever the candidates have in common
+            let common = rest.iter().fold(first.as_str(), |common, candidate| {
+                let len = common
+                    .char_indices()
+                    .zip(candidate.chars())
+                    .take_while(|((_, a), b)| a == b)
+                    .last()
+                    .map_or(0, |((i, a), _)| i + a.len_utf8());
+                &common[..len]
+            });
+            (Some(format!("{prefix}{common}")), candidates)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arguments() {
+        assert!(
+            matches!(parse("me waves hello"), Ok(Command::Me(action)) if action == "waves hello")
+        );
+        assert!(matches!(parse("nick  ada "), Ok(Command::Nick(name)) if name == "ada"));
+        assert!(matches!(parse("quit"), Ok(Command::Quit)));
+        assert!(matches!(
+            parse("ban bob"),
+            Ok(Command::Moderate(Sanction::Ban, peer)) if peer == "bob"
+        ));
+    }
+
+    #[test]
+    fn parses_help() {
+        assert!(matches!(parse("help"), Ok(Command::Help(None))));
+        assert!(
+            matches!(parse("help /kick"), Ok(Command::Help(Some(spec))) if spec.name == "kick")
+        );
+        assert_eq!(
+            parse("help frobnicate").err().unwrap(),
+            "Unknown command /frobnicate, try /help"
+        );
+    }
+
+    #[test]
+    fn rejects_bad_usage() {
+        assert_eq!(
+            parse("frobnicate").err().unwrap(),
+            "Unknown command /frobnicate, try /help"
+        );
+        assert_eq!(parse("quit now").err().unwrap(), "Usage: /quit");
+        assert_eq!(parse("topic").err().unwrap(), "Usage: /topic <topic>");
+        assert_eq!(
+            parse("kick bob alice").err().unwrap(),
+            "Usage: /kick <peer>"
+        );
+        assert!(parse(&format!("nick {}", "a".repeat(MAX_NICK_LEN + 1))).is_err());
+    }
+
+    #[test]
+    fn completes_command_names() {
+        assert_eq!(
+            complete("he", &[]),
+            (Some("/help ".to_string()), Vec::new())
+        );
+        assert_eq!(
+            complete("un", &[]),
+            (
+                Some("/un".to_string()),
+                vec!["unban".to_string(), "unmute".to_string()]
+            )
+        );
+        assert_eq!(complete("x", &[]), (None, Vec::new()));
+        assert_eq!(
+            complete("help ki", &[]),
+            (Some("/help kick ".to_string()), Vec::new())
+        );
+    }
+
+    #[test]
+    fn completes_peers() {
+        let peers = ["alice", "alfred", "bob"];
+        assert_eq!(
+            complete("kick b", &peers),
+            (Some("/kick bob ".to_string()), Vec::new())
+        );
+        assert_eq!(
+            complete("mute al", &peers),
+            (
+                Some("/mute al".to_string()),
+                vec!["alice".to_string(), "alfred".to_string()]
+            )
+        );
+        // Only commands taking a peer complete one, and only its first word
+        assert_eq!(complete("topic b", &peers), (None, Vec::new()));
+        assert_eq!(complete("kick bob b", &peers), (None, Vec::new()));
+    }
+}
@@ -1,3 +1,5 @@
 mod app;
+pub mod command;
+mod recent;
 
 pub use app::*;
@@ -0,0 +1,41 @@
+use std::fs;
+
+use postcard::{from_bytes, to_allocvec};
+use serde::{Deserialize, Serialize};
+
+const MAX_RECENT: usize = 10;
+
+/// A room the user joined before, remembered across restarts
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RecentRoom {
+    pub name: Option<String>,
+    pub host: String,
+}
+
+pub fn load() -> Vec<RecentRoom> {
+    let Some(path) = vlawn::paths::data_dir().map(|d| d.join("recent")) else {
+        return Vec::new();
+    };
+    fs::read(&path)
+        .ok()
+        .and_then(|bytes| from_bytes(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// Move `room` to the front of the recent list and persist it
+pub fn record(recent: &mut Vec<RecentRoom>, room: RecentRoom) {
+    recent.retain(|r| r.host != room.host);
+    recent.insert(0, room);
+    recent.truncate(MAX_RECENT);
+
+    let Some(dir) = vlawn::paths::data_dir() else {
+        return;
+    };
+    let result = fs::create_dir_all(&dir).and_then(|_| {
+        let bytes = to_allocvec(recent).map_err(std::io::Error::other)?;
+        fs::write(dir.join("recent"), bytes)
+    });
+    if let Err(e) = result {
+        log::warn!("Failed to save recent rooms: {e}");
+    }
+}